@@ -0,0 +1,208 @@
+use super::codegen::Rom;
+use super::disasm::{self, Instruction, Line, Mode, Operand, State};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+// Bytes of data written on each line
+const DATA_WIDTH: usize = 8;
+
+/// Assembler source for ca65 that builds to the same code and data as
+/// the ROM. Bank 0 goes in the CODE segment, and each other bank in a
+/// BANKxx segment, which the linker config needs to place at the
+/// bank's address. The header and vectors are left to the linker too.
+pub fn ca65(rom: &Rom) -> String {
+    let mut output = "; Generated by snazzy\n.p816\n.smart -\n\n".to_string();
+
+    // VARs are whatever isn't in the ROM itself
+    let mut vars = BTreeMap::new();
+    for symbol in rom.symbols.iter().filter(|symbol| symbol.offset.is_none()) {
+        writeln!(output, "{} = ${:04X}", symbol.name, symbol.addr).unwrap();
+        vars.insert(symbol.addr as u32, symbol.name);
+    }
+    if !vars.is_empty() {
+        output.push('\n');
+    }
+    let exports: Vec<_> = rom
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.offset.is_some())
+        .map(|symbol| symbol.name)
+        .collect();
+    if !exports.is_empty() {
+        writeln!(output, ".export {}\n", exports.join(", ")).unwrap();
+    }
+
+    let chunks: Vec<_> = rom
+        .chunks
+        .iter()
+        .map(|chunk| {
+            let bytes = &rom.image[chunk.offset..chunk.offset + chunk.len];
+            let lines = match chunk.data {
+                true => vec![],
+                false => disasm::disassemble(bytes, chunk.addr as u32, chunk.state),
+            };
+            (chunk, bytes, lines)
+        })
+        .collect();
+    let labels = labels(rom, chunks.iter().flat_map(|(_, _, lines)| lines));
+
+    let mut bank = None;
+    let mut owner = None;
+    let mut widths = (None, None);
+    for (chunk, bytes, lines) in &chunks {
+        if bank != Some(chunk.addr >> 16) {
+            bank = Some(chunk.addr >> 16);
+            match chunk.addr >> 16 {
+                0 => writeln!(output, ".segment \"CODE\"").unwrap(),
+                bank => writeln!(output, ".segment \"BANK{:02X}\"", bank).unwrap(),
+            }
+        }
+        if owner != Some(chunk.owner) {
+            writeln!(output, "\n{}:", chunk.owner).unwrap();
+            owner = Some(chunk.owner);
+        }
+        if chunk.data {
+            write_bytes(&mut output, bytes);
+            continue;
+        }
+        for line in lines {
+            if let Some(label) = labels.get(&line.addr) {
+                if *label != chunk.owner {
+                    writeln!(output, "{}:", label).unwrap();
+                }
+            }
+            let instruction = match line.instruction {
+                Some(instruction) => instruction,
+                None => {
+                    write_bytes(&mut output, line.bytes);
+                    continue;
+                }
+            };
+            set_widths(&mut output, &mut widths, line.state);
+            let operand = operand(&instruction, line.addr, &labels, &vars);
+            let text = format!("{} {}", instruction.mnemonic.to_lowercase(), operand);
+            writeln!(output, "  {}", text.trim_end()).unwrap();
+        }
+    }
+    output
+}
+
+fn write_bytes(output: &mut String, bytes: &[u8]) {
+    for data in bytes.chunks(DATA_WIDTH) {
+        let values: Vec<_> = data.iter().map(|byte| format!("${:02X}", byte)).collect();
+        writeln!(output, "  .byte {}", values.join(", ")).unwrap();
+    }
+}
+
+// Every function and piece of data gets its own name. Anything else
+// that's jumped to gets a local label: a loop if it's jumped back to,
+// or else something that's skipped ahead to.
+fn labels<'r, 'b: 'r>(
+    rom: &Rom<'r>,
+    lines: impl Iterator<Item = &'r Line<'b>>,
+) -> BTreeMap<u32, String> {
+    let mut labels: BTreeMap<u32, String> = rom
+        .symbols
+        .iter()
+        .filter(|symbol| symbol.offset.is_some())
+        .map(|symbol| (symbol.addr as u32, symbol.name.to_string()))
+        .collect();
+    let mut targets = BTreeMap::new();
+    for line in lines {
+        if let Some(target) = line.instruction.as_ref().and_then(|i| target(i, line.addr)) {
+            let backward = targets.entry(target).or_insert(false);
+            *backward |= target <= line.addr;
+        }
+    }
+    let mut count = (0, 0);
+    for (target, backward) in targets {
+        if labels.contains_key(&target) {
+            continue;
+        }
+        let label = if backward {
+            count.0 += 1;
+            format!("@loop{}", count.0)
+        } else {
+            count.1 += 1;
+            format!("@skip{}", count.1)
+        };
+        labels.insert(target, label);
+    }
+    labels
+}
+
+// Where a branch, jump or call goes to, when it's known
+fn target(instruction: &Instruction, addr: u32) -> Option<u32> {
+    match (instruction.mnemonic, instruction.operand) {
+        (_, Operand::Target(target)) => Some(target),
+        ("JMP" | "JSR", Operand::Address(Mode::Absolute, value)) => {
+            Some((addr & 0xFF_0000) | value)
+        }
+        ("JML" | "JSL", Operand::Address(Mode::Long, value)) => Some(value),
+        _ => None,
+    }
+}
+
+// Let ca65 know the size of immediates from here on
+fn set_widths(output: &mut String, widths: &mut (Option<bool>, Option<bool>), state: State) {
+    let wide_math = state.wide_math && !state.emulation;
+    let wide_index = state.wide_index && !state.emulation;
+    if widths.0 != Some(wide_math) {
+        writeln!(output, "  .a{}", if wide_math { 16 } else { 8 }).unwrap();
+        widths.0 = Some(wide_math);
+    }
+    if widths.1 != Some(wide_index) {
+        writeln!(output, "  .i{}", if wide_index { 16 } else { 8 }).unwrap();
+        widths.1 = Some(wide_index);
+    }
+}
+
+// Operands are written with an explicit size wherever ca65 might pick
+// a different one, since a VAR's value alone doesn't say
+fn operand(
+    instruction: &Instruction,
+    addr: u32,
+    labels: &BTreeMap<u32, String>,
+    vars: &BTreeMap<u32, &str>,
+) -> String {
+    let digits = (instruction.len - 1) * 2;
+    if let Some(target) = target(instruction, addr) {
+        return match labels.get(&target) {
+            Some(label) => label.clone(),
+            None => format!("${:06X}", target),
+        };
+    }
+    let (mode, value) = match instruction.operand {
+        Operand::None => return String::new(),
+        Operand::Accumulator => return "a".to_string(),
+        Operand::Immediate(value) => return format!("#${:01$X}", value, digits),
+        Operand::BlockMove(dest, src) => return format!("${:02X}, ${:02X}", src, dest),
+        Operand::Target(_) => unreachable!(),
+        Operand::Address(mode, value) => (mode, value),
+    };
+    let name = match mode {
+        Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY | Mode::Long | Mode::LongX => {
+            vars.get(&value).map(|name| name.to_string())
+        }
+        _ => None,
+    };
+    let value = name.unwrap_or_else(|| format!("${:01$X}", value, digits));
+    match mode {
+        Mode::Direct => format!("z:{}", value),
+        Mode::DirectX => format!("z:{},x", value),
+        Mode::DirectY => format!("z:{},y", value),
+        Mode::DirectIndirect | Mode::AbsoluteIndirect => format!("({})", value),
+        Mode::DirectIndirectX | Mode::AbsoluteIndirectX => format!("({},x)", value),
+        Mode::DirectIndirectY => format!("({}),y", value),
+        Mode::DirectIndirectLong | Mode::AbsoluteIndirectLong => format!("[{}]", value),
+        Mode::DirectIndirectLongY => format!("[{}],y", value),
+        Mode::Absolute => format!("a:{}", value),
+        Mode::AbsoluteX => format!("a:{},x", value),
+        Mode::AbsoluteY => format!("a:{},y", value),
+        Mode::Long => format!("f:{}", value),
+        Mode::LongX => format!("f:{},x", value),
+        Mode::Stack => format!("{},s", value),
+        Mode::StackIndirectY => format!("({},s),y", value),
+        _ => value,
+    }
+}
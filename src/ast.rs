@@ -1,3 +1,5 @@
+use std::fmt;
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Program<'a> {
     pub definitions: Vec<Definition<'a>>,
@@ -5,10 +7,62 @@ pub struct Program<'a> {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Definition<'a> {
+    Data(Data<'a>),
     Function(Function<'a>),
+    Header(Header<'a>),
     Var(Var<'a>),
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Header<'a> {
+    pub fields: Vec<HeaderItem<'a>>,
+    pub span: Span<'a>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeaderItem<'a> {
+    pub field: HeaderField<'a>,
+    pub span: Span<'a>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum HeaderField<'a> {
+    Title(&'a str),
+    MemoryMap(MemoryMap),
+    Region(u32),
+    RomSize(u32),
+    SramSize(u32),
+    Version(u32),
+    Developer(u32),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MemoryMap {
+    LoRom,
+    HiRom,
+    ExHiRom,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Data<'a> {
+    pub attributes: Vec<Attribute>,
+    pub contents: DataContents<'a>,
+    pub name: &'a str,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataContents<'a> {
+    Table(Width, Vec<u32>),
+    Binary(&'a str),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Width {
+    Byte,
+    Word,
+    Long,
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct Var<'a> {
     pub address: u32,
@@ -24,11 +78,31 @@ pub struct Function<'a> {
 #[derive(Clone, Debug, PartialEq)]
 pub struct Block<'a> {
     pub attributes: Vec<Attribute>,
-    pub instructions: Vec<Instruction<'a>>,
+    pub instructions: Vec<Statement<'a>>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement<'a> {
+    pub instruction: Instruction<'a>,
+    pub span: Span<'a>,
+}
+
+impl<'a> From<Instruction<'a>> for Statement<'a> {
+    fn from(instruction: Instruction<'a>) -> Self {
+        Statement {
+            instruction,
+            span: Span::default(),
+        }
+    }
 }
 
+/// The slice of source text a node was parsed from
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Span<'a>(pub &'a str);
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Attribute {
+    Bank(u32),
     Emulation,
     Extern,
     Interrupt,
@@ -44,21 +118,59 @@ pub enum Instruction<'a> {
     Assign(Operand<'a>, Operand<'a>),
     AndAssign(Operand<'a>, Operand<'a>),
     OrAssign(Operand<'a>, Operand<'a>),
+    AddAssign(Operand<'a>, Operand<'a>),
+    AddCarryAssign(Operand<'a>, Operand<'a>),
+    SubAssign(Operand<'a>, Operand<'a>),
+    SubCarryAssign(Operand<'a>, Operand<'a>),
+    XorAssign(Operand<'a>, Operand<'a>),
+    ShiftLeftAssign(Operand<'a>, Operand<'a>),
+    ShiftRightAssign(Operand<'a>, Operand<'a>),
+    RotateLeftAssign(Operand<'a>, Operand<'a>),
+    RotateRightAssign(Operand<'a>, Operand<'a>),
+    Increment(Operand<'a>),
+    Decrement(Operand<'a>),
     Block(Block<'a>),
     Call(&'a str),
-    If(Block<'a>, Conditional<'a>),
+    If(Block<'a>, Conditional<'a>, Option<Block<'a>>),
     Loop(Block<'a>, Option<Conditional<'a>>),
+    While(Block<'a>, Conditional<'a>),
+    For(Block<'a>, Register, u32, u32),
     Push(Operand<'a>),
     Pop(Operand<'a>),
+    Break,
+    Continue,
     Cli,
     Sei,
+    /// Exchange the two bytes of the accumulator
+    Swap,
+    /// Raw bytes, followed by the modes they leave the processor in
+    Bytes(Vec<u32>, Vec<Attribute>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Conditional<'a> {
     BitTest(Operand<'a>, Operand<'a>),
     Equality(Operand<'a>, Operand<'a>),
+    Flag(Flag),
+    Greater(Operand<'a>, Operand<'a>),
+    GreaterEqual(Operand<'a>, Operand<'a>),
+    Inequality(Operand<'a>, Operand<'a>),
+    Less(Operand<'a>, Operand<'a>),
+    LessEqual(Operand<'a>, Operand<'a>),
     NotBitTest(Operand<'a>, Operand<'a>),
+    NotFlag(Flag),
+    SignedGreater(Operand<'a>, Operand<'a>),
+    SignedGreaterEqual(Operand<'a>, Operand<'a>),
+    SignedLess(Operand<'a>, Operand<'a>),
+    SignedLessEqual(Operand<'a>, Operand<'a>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Flag {
+    Carry,
+    Negative,
+    Overflow,
+    Zero,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -82,4 +194,47 @@ pub enum Operand<'a> {
     Absolute(u32),
     Register(Register),
     Variable(&'a str),
+    Direct(u32),
+    Stack(u32),
+    Indirect(Box<Operand<'a>>),
+    IndirectLong(Box<Operand<'a>>),
+    Indexed(Box<Operand<'a>>, Register),
+}
+
+impl fmt::Display for Attribute {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Attribute::Bank(bank) => write!(f, "BANK({})", bank),
+            Attribute::Emulation => write!(f, "EMU"),
+            Attribute::Extern => write!(f, "EXTERN"),
+            Attribute::Interrupt => write!(f, "INTR"),
+            Attribute::NarrowIndex => write!(f, "NARROWX"),
+            Attribute::NarrowMath => write!(f, "NARROWM"),
+            Attribute::Native => write!(f, "NAT"),
+            Attribute::WideIndex => write!(f, "WIDEX"),
+            Attribute::WideMath => write!(f, "WIDEM"),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Operand<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Immediate(value) => write!(f, "0x{:X}", value),
+            Operand::Absolute(addr) => write!(f, "*0x{:X}", addr),
+            Operand::Register(reg) => write!(f, "{}", reg),
+            Operand::Variable(name) => write!(f, "{}", name),
+            Operand::Direct(offset) => write!(f, "D[0x{:X}]", offset),
+            Operand::Stack(offset) => write!(f, "S[{}]", offset),
+            Operand::Indirect(inner) => write!(f, "({})", inner),
+            Operand::IndirectLong(inner) => write!(f, "[{}]", inner),
+            Operand::Indexed(inner, reg) => write!(f, "{}[{}]", inner, reg),
+        }
+    }
 }
@@ -1,31 +1,312 @@
-use snazzy::{codegen, parser};
-use std::io::{Read, Write};
+use snazzy::disasm::{self, State};
+use snazzy::emulator::Bus;
+use snazzy::{assembly, ast, codegen, diagnostic::Diagnostic, listing, parser, symbols};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+const USAGE: &str = "\
+usage: snazzy [options] <input.snz>
+       snazzy disasm [options] <rom>
+
+options:
+  -o, --output <path>    where to write the output (default: <input>.bin,
+                         or <input>.s for ca65)
+  -f, --format <format>  output format: bin (default), or ca65 source
+      --listing <path>   also write an assembly listing
+      --symbols <path>   also write a symbol file, for Mesen if it ends
+                         in .mlb or WLA/no$sns .sym otherwise
+  -D <name>=<value>      define a VAR, overriding one in the source
+  -q, --quiet            don't print diagnostics, only set the exit code
+  -v, --verbose          print what was written
+  -h, --help             show this message";
+
+const DISASM_USAGE: &str = "\
+usage: snazzy disasm [options] <rom>
+
+options:
+      --start <addr>     where to start (default: the reset vector)
+      --length <bytes>   how much to decode (default: the rest of the bank)
+      --native           start in native mode, rather than emulation
+      --widem            start with a 16-bit accumulator
+      --widex            start with 16-bit index registers
+  -h, --help             show this message";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Bin,
+    Ca65,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+struct Options {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    format: Format,
+    listing: Option<PathBuf>,
+    symbols: Option<PathBuf>,
+    defines: Vec<(String, u32)>,
+    verbosity: Verbosity,
+}
+
+struct DisasmOptions {
+    input: PathBuf,
+    start: Option<u32>,
+    length: Option<u32>,
+    state: State,
+}
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    println!("{:?}", args);
-    let in_path = std::path::Path::new(&args[1]);
-    let out_path = in_path.with_extension("bin");
-
-    let mut code = String::new();
-    let mut input = std::fs::File::open(in_path).expect("Could not open input file");
-    input
-        .read_to_string(&mut code)
-        .expect("Could not read code from file");
-    let ast = match parser::program(&code) {
-        Err(nom::Err::Error(e)) | Err(nom::Err::Failure(e)) => {
-            for err in e.errors {
-                println!("{:?} {:?}", err.1, err.0);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("disasm") {
+        return disasm_main(&args[1..]);
+    }
+    let options = match parse_args(&args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, USAGE);
+            exit(2);
+        }
+    };
+    if let Err(message) = run(&options) {
+        if options.verbosity != Verbosity::Quiet {
+            eprint!("{}", message);
+        }
+        exit(1);
+    }
+}
+
+// Returns `None` when help was asked for
+fn parse_args(args: &[String]) -> Result<Option<Options>, String> {
+    let mut input = None;
+    let mut options = Options {
+        input: PathBuf::new(),
+        output: None,
+        format: Format::Bin,
+        listing: None,
+        symbols: None,
+        defines: vec![],
+        verbosity: Verbosity::Normal,
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "-o" | "--output" => options.output = Some(value()?.into()),
+            "-f" | "--format" => {
+                options.format = match value()?.as_str() {
+                    "bin" => Format::Bin,
+                    "ca65" => Format::Ca65,
+                    other => return Err(format!("unknown format `{}`", other)),
+                }
+            }
+            "--listing" => options.listing = Some(value()?.into()),
+            "--symbols" => options.symbols = Some(value()?.into()),
+            "-D" => options.defines.push(parse_define(value()?)?),
+            "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => options.verbosity = Verbosity::Verbose,
+            define if define.starts_with("-D") => options.defines.push(parse_define(&define[2..])?),
+            flag if flag.starts_with('-') => return Err(format!("unknown option `{}`", flag)),
+            path => {
+                if input.replace(PathBuf::from(path)).is_some() {
+                    return Err("only one input file can be given".to_string());
+                }
             }
-            panic!("Error parsing input");
         }
-        Err(nom::Err::Incomplete(_)) => panic!("Incomplete input"),
-        Ok(ast) => ast,
+    }
+    options.input = input.ok_or_else(|| "no input file given".to_string())?;
+    Ok(Some(options))
+}
+
+fn parse_define(define: &str) -> Result<(String, u32), String> {
+    let bad = || format!("bad define `{}`, expected <name>=<value>", define);
+    let (name, value) = define.split_once('=').ok_or_else(bad)?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase() || c == '_') {
+        return Err(bad());
+    }
+    let value = parse_number(value).ok_or_else(bad)?;
+    Ok((name.to_string(), value))
+}
+
+// Decimal, or hex with a 0x
+fn parse_number(value: &str) -> Option<u32> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+// Any error comes back as the text to report
+fn run(options: &Options) -> Result<(), String> {
+    let display = options.input.display().to_string();
+    let code = std::fs::read_to_string(&options.input)
+        .map_err(|e| format!("error: couldn't read {}: {}\n", display, e))?;
+
+    let mut ast =
+        parser::program(&code).map_err(|e| Diagnostic::from_parse(&e).render(&display, &code))?;
+    for (name, address) in &options.defines {
+        ast.definitions.push(ast::Definition::Var(ast::Var {
+            address: *address,
+            name,
+        }));
+    }
+
+    let base = options.input.parent().unwrap_or_else(|| Path::new(""));
+    let rom = codegen::assemble_rom(&ast, base).map_err(|errors| {
+        errors
+            .iter()
+            .map(|error| Diagnostic::from_codegen(error).render(&display, &code))
+            .collect::<String>()
+    })?;
+
+    let output = match &options.output {
+        Some(output) => output.clone(),
+        None => options.input.with_extension(match options.format {
+            Format::Bin => "bin",
+            Format::Ca65 => "s",
+        }),
+    };
+    let contents = match options.format {
+        Format::Bin => rom.image.clone(),
+        Format::Ca65 => assembly::ca65(&rom).into_bytes(),
     };
-    let image = codegen::assemble(&ast).expect("Codegen error with program");
+    write(&output, &contents, options.verbosity)?;
+    if let Some(path) = &options.listing {
+        write(
+            path,
+            listing::write(&rom, &code).as_bytes(),
+            options.verbosity,
+        )?;
+    }
+    if let Some(path) = &options.symbols {
+        let contents = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("mlb") => symbols::mlb(&rom),
+            _ => symbols::sym(&rom),
+        };
+        write(path, contents.as_bytes(), options.verbosity)?;
+    }
+    Ok(())
+}
+
+fn write(path: &Path, contents: &[u8], verbosity: Verbosity) -> Result<(), String> {
+    std::fs::write(path, contents)
+        .map_err(|e| format!("error: couldn't write {}: {}\n", path.display(), e))?;
+    if verbosity == Verbosity::Verbose {
+        println!("wrote {} bytes to {}", contents.len(), path.display());
+    }
+    Ok(())
+}
 
-    let mut output = std::fs::File::create(&out_path).expect("Could not open output file");
-    output
-        .write_all(&image)
-        .expect("Could not write image to file");
+fn disasm_main(args: &[String]) {
+    let options = match parse_disasm_args(args) {
+        Ok(Some(options)) => options,
+        Ok(None) => {
+            println!("{}", DISASM_USAGE);
+            return;
+        }
+        Err(message) => {
+            eprintln!("error: {}\n\n{}", message, DISASM_USAGE);
+            exit(2);
+        }
+    };
+    match disassemble(&options) {
+        Ok(text) => print!("{}", text),
+        Err(message) => {
+            eprint!("{}", message);
+            exit(1);
+        }
+    }
+}
+
+// Returns `None` when help was asked for
+fn parse_disasm_args(args: &[String]) -> Result<Option<DisasmOptions>, String> {
+    let mut input = None;
+    let mut options = DisasmOptions {
+        input: PathBuf::new(),
+        start: None,
+        length: None,
+        state: State {
+            emulation: true,
+            wide_math: false,
+            wide_index: false,
+        },
+    };
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut number = || {
+            let value = args
+                .next()
+                .ok_or_else(|| format!("{} needs a value", arg))?;
+            parse_number(value).ok_or_else(|| format!("bad number `{}`", value))
+        };
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(None),
+            "--start" => options.start = Some(number()?),
+            "--length" => options.length = Some(number()?),
+            "--native" => options.state.emulation = false,
+            "--widem" => options.state.wide_math = true,
+            "--widex" => options.state.wide_index = true,
+            flag if flag.starts_with('-') => return Err(format!("unknown option `{}`", flag)),
+            path => {
+                if input.replace(PathBuf::from(path)).is_some() {
+                    return Err("only one ROM can be given".to_string());
+                }
+            }
+        }
+    }
+    if options.state.emulation && (options.state.wide_math || options.state.wide_index) {
+        return Err("--widem and --widex need --native".to_string());
+    }
+    options.input = input.ok_or_else(|| "no ROM given".to_string())?;
+    Ok(Some(options))
+}
+
+fn disassemble(options: &DisasmOptions) -> Result<String, String> {
+    let image = std::fs::read(&options.input)
+        .map_err(|e| format!("error: couldn't read {}: {}\n", options.input.display(), e))?;
+    let bus = Bus::new(image);
+    let start = options.start.unwrap_or_else(|| bus.read16(0xFFFC) as u32) & 0xFF_FFFF;
+    let bytes: Vec<u8> = match options.length {
+        Some(length) => {
+            let end = start
+                .checked_add(length)
+                .filter(|end| *end <= 0x100_0000)
+                .ok_or_else(|| {
+                    format!(
+                        "error: --length 0x{:X} runs past the end of the address space\n",
+                        length
+                    )
+                })?;
+            (start..end).map(|addr| bus.read(addr)).collect()
+        }
+        None => {
+            // Stop short of the header in the banks it's mapped into,
+            // and leave off any padding
+            let end = match start >> 16 {
+                0x00 | 0x80 => (start & 0xFF_0000) | 0xFFC0,
+                _ => (start & 0xFF_0000) + 0x10000,
+            };
+            let mut bytes: Vec<u8> = (start..end.max(start)).map(|addr| bus.read(addr)).collect();
+            let len = bytes
+                .iter()
+                .rposition(|byte| *byte != 0)
+                .map_or(0, |i| i + 1);
+            bytes.truncate(len);
+            bytes
+        }
+    };
+    Ok(disasm::disassemble(&bytes, start, options.state)
+        .iter()
+        .map(|line| format!("{}\n", line))
+        .collect())
 }
@@ -1,19 +1,68 @@
 use super::ast::*;
+use super::disasm::State;
 use std::collections::BTreeMap;
+use std::fmt;
+use std::path::Path;
 
-struct Bank {
+struct Bank<'a> {
     start: usize,
+    offset: usize,
     len: usize,
     code: Vec<u8>,
+    // Offsets are relative to the bank until the ROM is built
+    chunks: Vec<Chunk<'a>>,
+    // What's being assembled, kept up to date by `Context::bank` so
+    // that each chunk can be tagged with it
+    state: State,
+    span: Option<Span<'a>>,
 }
 
 struct Context<'a> {
-    bank: Bank,
+    map: MemoryMap,
+    banks: Vec<Bank<'a>>,
+    current_bank: usize,
     emulation: bool,
     wide_math: bool,
     wide_index: bool,
     names: BTreeMap<&'a str, Name>,
     relocations: Vec<Relocation<'a>>,
+    loops: Vec<Loop>,
+    jumps: Vec<Jump>,
+    span: Option<Span<'a>>,
+    errors: Vec<LocatedError<'a>>,
+}
+
+struct Checkpoint {
+    code: usize,
+    relocations: usize,
+    jumps: usize,
+    errors: usize,
+    emulation: bool,
+    wide_math: bool,
+    wide_index: bool,
+}
+
+// The innermost loops being assembled, along with the modes expected
+// at their start and after they exit
+#[derive(Clone)]
+struct Loop {
+    start: usize,
+    conditional: bool,
+    emulation: bool,
+    wide_math: bool,
+    wide_index: bool,
+    exit_emulation: bool,
+    exit_wide_math: bool,
+    exit_wide_index: bool,
+}
+
+// A jump or branch in the function being assembled whose target is
+// known. Widening a branch moves the code after it, so every jump has
+// to be pointed at its target again.
+#[derive(Clone, Copy)]
+struct Jump {
+    site: usize,
+    target: usize,
 }
 
 #[derive(Clone)]
@@ -24,8 +73,10 @@ enum Name {
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Relocation<'a> {
-    Function(&'a str, usize),
+    Function(&'a str, usize, usize),
+    LongFunction(&'a str, usize, usize),
     Break(usize),
+    Continue(usize),
 }
 
 #[derive(Debug, PartialEq)]
@@ -33,149 +84,590 @@ pub enum Error<'a> {
     BadAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
     BadAndAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
     BadOrAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadAddAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadSubAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadXorAssignment(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadShift(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadIncrement(&'a Operand<'a>, &'a str),
+    BadDecrement(&'a Operand<'a>, &'a str),
     BadBitTest(&'a Operand<'a>, &'a Operand<'a>, &'a str),
     BadEquality(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    BadComparison(&'a Operand<'a>, &'a Operand<'a>, &'a str),
+    SignedIndexCompare(Register, &'a str),
     BadPush(&'a Operand<'a>, &'a str),
     BadPop(&'a Operand<'a>, &'a str),
+    BadOperand(&'a Operand<'a>, &'a str),
+    FarOperand(&'a Operand<'a>, &'a str),
     ConflictingAttributes(Attribute, Attribute, &'a str),
     NoSpace(&'static str, &'a str),
-    LoopTooLong(&'a str),
-    IfTooLong(&'a str),
+    BreakOutsideLoop(&'a str),
+    ContinueOutsideLoop(&'a str),
+    CountDownToZero(Register, &'a str),
     InvalidAddress(u32, &'a str),
     InvalidValue(u32, &'a str),
     InvalidRegister(Register, Attribute, &'a str),
     UnknownVariable(&'a str, &'a str),
     UnknownFunction(&'a str, &'a str),
+    FarCall(&'a str, &'a str),
     InvalidInterrupt(&'static str),
+    IncludeFailed(&'a str, std::io::ErrorKind),
+    InvalidInterruptBank(&'static str),
+    InvalidBank(u32, &'a str),
+    DuplicateHeader,
+    DuplicateHeaderField(&'static str),
+    InvalidTitle(&'a str),
+    TitleTooLong(&'a str),
+    InvalidHeaderValue(&'static str, u32),
     UnresolvedName(Relocation<'a>),
 }
 
 pub type Result<'a> = std::result::Result<(), Error<'a>>;
 
-pub fn assemble<'p>(program: &'p Program<'p>) -> std::result::Result<Vec<u8>, Error<'p>> {
+/// A run of bytes emitted in one go: either a few instructions, or a
+/// table of data
+#[derive(Clone, Copy, Debug)]
+pub struct Chunk<'a> {
+    /// The 24-bit address the bytes are mapped at
+    pub addr: usize,
+    /// Where the bytes are in the ROM image
+    pub offset: usize,
+    pub len: usize,
+    /// The function or data the bytes belong to
+    pub owner: &'a str,
+    pub span: Option<Span<'a>>,
+    pub data: bool,
+    /// The processor modes the first instruction runs in
+    pub state: State,
+}
+
+/// An assembled ROM image, along with what went where in it
+pub struct Rom<'a> {
+    pub image: Vec<u8>,
+    pub chunks: Vec<Chunk<'a>>,
+    pub symbols: Vec<Symbol<'a>>,
+}
+
+/// A named address: a function, DATA or VAR
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Symbol<'a> {
+    pub name: &'a str,
+    pub addr: usize,
+    /// Where the address is in the ROM image, if it's in there at all
+    pub offset: Option<usize>,
+}
+
+/// An error along with the source it came from: the innermost
+/// statement being assembled, or else the function or data it was in.
+#[derive(Debug, PartialEq)]
+pub struct LocatedError<'a> {
+    pub error: Error<'a>,
+    pub span: Option<Span<'a>>,
+}
+
+impl fmt::Display for Error<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::BadAssignment(l, r, _) => write!(f, "can't assign {} to {}", r, l),
+            Error::BadAndAssignment(l, r, _) => write!(f, "can't AND {} into {}", r, l),
+            Error::BadOrAssignment(l, r, _) => write!(f, "can't OR {} into {}", r, l),
+            Error::BadAddAssignment(l, r, _) => write!(f, "can't add {} to {}", r, l),
+            Error::BadSubAssignment(l, r, _) => write!(f, "can't subtract {} from {}", r, l),
+            Error::BadXorAssignment(l, r, _) => write!(f, "can't XOR {} into {}", r, l),
+            Error::BadShift(l, r, _) => write!(f, "can't shift or rotate {} by {}", l, r),
+            Error::BadIncrement(op, _) => write!(f, "can't increment {}", op),
+            Error::BadDecrement(op, _) => write!(f, "can't decrement {}", op),
+            Error::BadBitTest(l, r, _) => write!(f, "can't test the bits of {} against {}", l, r),
+            Error::BadEquality(l, r, _) => write!(f, "can't check {} for equality with {}", l, r),
+            Error::BadComparison(l, r, _) => write!(f, "can't compare {} with {}", l, r),
+            Error::SignedIndexCompare(reg, _) => write!(
+                f,
+                "signed comparisons only work on A or C, so {} has to be copied into one first",
+                reg
+            ),
+            Error::BadPush(op, _) => write!(f, "can't push {}", op),
+            Error::BadPop(op, _) => write!(f, "can't pop into {}", op),
+            Error::BadOperand(op, _) => write!(f, "{} isn't a valid addressing mode", op),
+            Error::FarOperand(op, _) => write!(
+                f,
+                "{} is in another bank, so it needs long addressing, which only A and C have (and only indexed by X)",
+                op
+            ),
+            Error::ConflictingAttributes(a, b, _) => {
+                write!(f, "attributes {} and {} conflict", a, b)
+            }
+            Error::NoSpace(_, function) => write!(f, "no space left in the bank for {}", function),
+            Error::BreakOutsideLoop(_) => write!(f, "BREAK outside of a loop"),
+            Error::ContinueOutsideLoop(_) => write!(f, "CONTINUE outside of a loop"),
+            Error::CountDownToZero(reg, _) => write!(
+                f,
+                "FOR can't count {} down to 0, since it stops as soon as it gets there; count down TO 1 instead",
+                reg
+            ),
+            Error::InvalidAddress(addr, _) => write!(f, "address 0x{:X} is out of range", addr),
+            Error::InvalidValue(value, _) => write!(f, "value 0x{:X} is out of range", value),
+            Error::InvalidRegister(reg, attr, _) => {
+                write!(f, "register {} can't be used with {}", reg, attr)
+            }
+            Error::UnknownVariable(name, _) => write!(f, "unknown variable `{}`", name),
+            Error::UnknownFunction(name, _) => write!(f, "unknown function `{}`", name),
+            Error::FarCall(name, _) => write!(
+                f,
+                "`{}` is in another bank, so it must be EXTERN to be called",
+                name
+            ),
+            Error::InvalidInterrupt(name) => write!(f, "interrupt handler `{}` must be INTR", name),
+            Error::IncludeFailed(path, kind) => {
+                write!(f, "couldn't include \"{}\": {:?}", path, kind)
+            }
+            Error::InvalidInterruptBank(name) => {
+                write!(f, "interrupt handler `{}` must be in bank 0", name)
+            }
+            Error::InvalidBank(bank, _) => {
+                write!(f, "bank {} doesn't exist in this memory map", bank)
+            }
+            Error::DuplicateHeader => write!(f, "only one HEADER is allowed"),
+            Error::DuplicateHeaderField(field) => {
+                write!(f, "{} is set more than once in HEADER", field)
+            }
+            Error::InvalidTitle(title) => {
+                write!(f, "title \"{}\" must be printable ASCII", title)
+            }
+            Error::TitleTooLong(title) => {
+                write!(f, "title \"{}\" is longer than 21 characters", title)
+            }
+            Error::InvalidHeaderValue(field, value) => {
+                write!(f, "invalid {} in HEADER: {}", field, value)
+            }
+            Error::UnresolvedName(Relocation::Function(name, _, _))
+            | Error::UnresolvedName(Relocation::LongFunction(name, _, _)) => {
+                write!(f, "call to undefined function `{}`", name)
+            }
+            Error::UnresolvedName(_) => write!(f, "unresolved loop jump"),
+        }
+    }
+}
+
+pub fn assemble<'p>(
+    program: &'p Program<'p>,
+) -> std::result::Result<Vec<u8>, Vec<LocatedError<'p>>> {
+    assemble_from(program, Path::new(""))
+}
+
+/// Assemble a program, loading any INCBIN files relative to `base`.
+/// Every error found along the way is returned, not just the first.
+pub fn assemble_from<'p>(
+    program: &'p Program<'p>,
+    base: &Path,
+) -> std::result::Result<Vec<u8>, Vec<LocatedError<'p>>> {
+    assemble_rom(program, base).map(|rom| rom.image)
+}
+
+/// Like `assemble_from`, but also keeps track of where each piece of
+/// code and data ended up, for listings and the like
+pub fn assemble_rom<'p>(
+    program: &'p Program<'p>,
+    base: &Path,
+) -> std::result::Result<Rom<'p>, Vec<LocatedError<'p>>> {
     let mut context = Context {
-        bank: Bank {
-            start: 0x8000,
-            len: 0x8000 - 0x40, // leave room for the header
-            code: vec![],
-        },
+        map: MemoryMap::LoRom,
+        banks: vec![],
+        current_bank: 0,
         emulation: false,
         wide_math: false,
         wide_index: false,
         names: BTreeMap::new(),
         relocations: vec![],
+        loops: vec![],
+        jumps: vec![],
+        span: None,
+        errors: vec![],
     };
 
-    // First, iterate over definitions to populate the name table
+    let header = collect_names(&mut context, program).map_err(|error| vec![error])?;
+    context.map = header.map;
+    context.banks.push(Bank::new(0, header.map));
+
+    // Data goes in ahead of any code in its bank, so that its address
+    // is always known by the time an instruction refers to it
     for def in &program.definitions {
-        match def {
-            Definition::Function(func) => context.names.insert(
-                func.name,
-                Name::Function(None, func.body.attributes.clone()),
-            ),
-            Definition::Var(var) => context.names.insert(var.name, Name::Var(var.address)),
-        };
+        if let Definition::Data(data) = def {
+            if let Err(error) = assemble_data(&mut context, data, base) {
+                context.fail(error);
+            }
+        }
     }
 
     // Then, start assembling the functions
     for def in &program.definitions {
         if let Definition::Function(function) = def {
-            assemble_function(&mut context, &function)?;
+            let modes = (context.emulation, context.wide_math, context.wide_index);
+            if let Err(error) = assemble_function(&mut context, function) {
+                context.fail(error);
+                context.emulation = modes.0;
+                context.wide_math = modes.1;
+                context.wide_index = modes.2;
+            }
         }
     }
 
-    if !context.relocations.is_empty() {
-        return Err(Error::UnresolvedName(context.relocations[0].clone()));
+    if !context.errors.is_empty() {
+        return Err(context.errors);
+    }
+    context.span = None;
+    let image = build_image(&mut context, &header).map_err(|error| vec![error])?;
+    let chunks = context
+        .banks
+        .iter()
+        .flat_map(|bank| {
+            bank.chunks.iter().map(move |chunk| Chunk {
+                offset: bank.offset + chunk.offset,
+                ..*chunk
+            })
+        })
+        .collect();
+    let mut symbols: Vec<_> = context
+        .names
+        .iter()
+        .filter_map(|(name, value)| {
+            let addr = match value {
+                Name::Function(Some(addr), _) => *addr,
+                Name::Var(addr) => *addr as usize,
+                Name::Function(None, _) => return None,
+            };
+            let offset = context
+                .banks
+                .iter()
+                .find(|bank| (bank.start..bank.addr()).contains(&addr))
+                .map(|bank| bank.offset + addr - bank.start);
+            Some(Symbol { name, addr, offset })
+        })
+        .collect();
+    symbols.sort_by_key(|symbol| (symbol.addr, symbol.name));
+    Ok(Rom {
+        image,
+        chunks,
+        symbols,
+    })
+}
+
+// Populate the name table, and find the header
+fn collect_names<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    program: &'p Program<'p>,
+) -> std::result::Result<CartridgeHeader<'p>, LocatedError<'p>> {
+    let mut header_fields = None;
+    for def in &program.definitions {
+        match def {
+            Definition::Data(_) => {}
+            Definition::Function(func) => {
+                context.names.insert(
+                    func.name,
+                    Name::Function(None, func.body.attributes.clone()),
+                );
+            }
+            Definition::Header(header) => {
+                if header_fields.is_some() {
+                    return Err(LocatedError {
+                        error: Error::DuplicateHeader,
+                        span: Some(header.span),
+                    });
+                }
+                header_fields = Some(header.fields.as_slice());
+            }
+            Definition::Var(var) => {
+                context.names.insert(var.name, Name::Var(var.address));
+            }
+        };
     }
+    CartridgeHeader::new(header_fields.unwrap_or(&[]))
+}
 
-    context.bank.code.resize(0x8000, 0);
-    for i in 0..22 {
-        context.bank.code[0x7FC0 + i] = b'Z';
+// Lay the banks out into a ROM image, and fill in the header
+fn build_image<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    header: &CartridgeHeader<'p>,
+) -> std::result::Result<Vec<u8>, LocatedError<'p>> {
+    if let Some(relocation) = context.relocations.first() {
+        return Err(LocatedError {
+            error: Error::UnresolvedName(relocation.clone()),
+            span: None,
+        });
     }
-    context.bank.code[0x7FD5] = 0x20; // LoROM
-    context.bank.code[0x7FD7] = 0x05; // 32KB ROM
-    context.bank.code[0x7FD9] = 0x01; // North american ROM
-    context.bank.code[0x7FDA] = 0x33;
-    // TODO: checksums
 
-    let mut code = context.bank.code.clone();
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("cop") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FE4] = bytes[0];
-            code[0x7FE5] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("cop"));
+    let bank_size = context.map.bank_size();
+    let image_size = context
+        .banks
+        .iter()
+        .map(|bank| (bank.offset + bank.len).div_ceil(bank_size) * bank_size)
+        .max()
+        .unwrap_or(0);
+    let rom_size = match header.rom_size {
+        Some(size) if (size as usize) * 1024 < image_size => {
+            return Err(header.error("rom_size", Error::InvalidHeaderValue("rom_size", size)));
         }
+        Some(size) => size,
+        None => (image_size as u32 / 1024).next_power_of_two(),
+    };
+
+    let mut code = vec![0; image_size];
+    for bank in &context.banks {
+        code[bank.offset..bank.offset + bank.code.len()].copy_from_slice(&bank.code);
     }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("brk") {
-        if attributes.contains(&Attribute::Interrupt) {
+    let header_offset = context.map.header_offset();
+    header.write(&mut code[header_offset..header_offset + 0x1C], rom_size);
+
+    // Vectors are relative to the start of the header
+    for (name, vector) in &[
+        ("cop", 0x24),
+        ("brk", 0x26),
+        ("nmi", 0x2A),
+        ("irq", 0x2E),
+        ("cop_emu", 0x34),
+        ("nmi_emu", 0x3A),
+        ("reset", 0x3C),
+        ("irq_emu", 0x3E),
+    ] {
+        if let Some((function, Name::Function(Some(addr), attributes))) =
+            context.names.get_key_value(name)
+        {
+            // The name is the one from the source, so errors can point
+            // at the function
+            let span = Some(Span(function));
+            if !attributes.contains(&Attribute::Interrupt) {
+                return Err(LocatedError {
+                    error: Error::InvalidInterrupt(name),
+                    span,
+                });
+            }
+            // The CPU always fetches interrupt handlers from bank 0
+            if *addr > 0xFFFF {
+                return Err(LocatedError {
+                    error: Error::InvalidInterruptBank(name),
+                    span,
+                });
+            }
             let bytes = addr.to_le_bytes();
-            code[0x7FE6] = bytes[0];
-            code[0x7FE7] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("brk"));
+            code[header_offset + vector] = bytes[0];
+            code[header_offset + vector + 1] = bytes[1];
         }
     }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("nmi") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FEA] = bytes[0];
-            code[0x7FEB] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("nmi"));
-        }
+
+    // An explicit ROM size pads the image out. Otherwise we leave it
+    // as-is, and the checksum mirrors the last banks to fill the space.
+    if header.rom_size.is_some() {
+        code.resize(rom_size as usize * 1024, 0);
     }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("irq") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FEE] = bytes[0];
-            code[0x7FEF] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("irq"));
+
+    // The checksum is calculated as if the complement and checksum
+    // fields already hold a valid pair, which always sums to 0x1FE
+    let checksum_offset = header_offset + 0x1C;
+    code[checksum_offset..checksum_offset + 4].copy_from_slice(&[0xFF, 0xFF, 0x00, 0x00]);
+    let checksum = checksum(&code);
+    let complement = (!checksum).to_le_bytes();
+    let checksum = checksum.to_le_bytes();
+    code[checksum_offset] = complement[0];
+    code[checksum_offset + 1] = complement[1];
+    code[checksum_offset + 2] = checksum[0];
+    code[checksum_offset + 3] = checksum[1];
+
+    Ok(code)
+}
+
+struct CartridgeHeader<'a> {
+    title: &'a str,
+    map: MemoryMap,
+    region: u32,
+    rom_size: Option<u32>,
+    sram_size: u32,
+    version: u32,
+    developer: u32,
+    spans: Vec<(&'static str, Span<'a>)>,
+}
+
+impl<'a> CartridgeHeader<'a> {
+    fn new(fields: &'a [HeaderItem<'a>]) -> std::result::Result<Self, LocatedError<'a>> {
+        let mut header = CartridgeHeader {
+            title: "ZZZZZZZZZZZZZZZZZZZZZ",
+            map: MemoryMap::LoRom,
+            region: 0x01, // North America
+            rom_size: None,
+            sram_size: 0,
+            version: 0,
+            developer: 0x33,
+            spans: vec![],
+        };
+        for item in fields {
+            let name = match &item.field {
+                HeaderField::Title(title) => {
+                    header.title = title;
+                    "title"
+                }
+                HeaderField::MemoryMap(map) => {
+                    header.map = *map;
+                    "map"
+                }
+                HeaderField::Region(region) => {
+                    header.region = *region;
+                    "region"
+                }
+                HeaderField::RomSize(size) => {
+                    header.rom_size = Some(*size);
+                    "rom_size"
+                }
+                HeaderField::SramSize(size) => {
+                    header.sram_size = *size;
+                    "sram_size"
+                }
+                HeaderField::Version(version) => {
+                    header.version = *version;
+                    "version"
+                }
+                HeaderField::Developer(developer) => {
+                    header.developer = *developer;
+                    "developer"
+                }
+            };
+            if header.span(name).is_some() {
+                return Err(LocatedError {
+                    error: Error::DuplicateHeaderField(name),
+                    span: Some(item.span),
+                });
+            }
+            header.spans.push((name, item.span));
         }
-    }
 
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("cop_emu") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FF4] = bytes[0];
-            code[0x7FF5] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("cop_emu"));
+        if header.title.len() > 21 {
+            return Err(header.error("title", Error::TitleTooLong(header.title)));
         }
-    }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("nmi_emu") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FFA] = bytes[0];
-            code[0x7FFB] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("nmi_emu"));
+        if !header.title.bytes().all(|b| (0x20..=0x7E).contains(&b)) {
+            return Err(header.error("title", Error::InvalidTitle(header.title)));
         }
-    }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("reset") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FFC] = bytes[0];
-            code[0x7FFD] = bytes[1];
-        } else {
-            return Err(Error::InvalidInterrupt("reset"));
+        if header.region > 0x14 {
+            return Err(header.error("region", Error::InvalidHeaderValue("region", header.region)));
+        }
+        // Sizes are given in KB, and are stored as a power of two. A ROM
+        // size in between is rounded up, and the checksum mirrors its
+        // last banks to fill the space.
+        if let Some(size) = header.rom_size {
+            if size % 32 != 0 || !(32..=8192).contains(&size) {
+                return Err(header.error("rom_size", Error::InvalidHeaderValue("rom_size", size)));
+            }
         }
+        if header.sram_size != 0
+            && (!header.sram_size.is_power_of_two() || !(2..=128).contains(&header.sram_size))
+        {
+            return Err(header.error(
+                "sram_size",
+                Error::InvalidHeaderValue("sram_size", header.sram_size),
+            ));
+        }
+        if header.version > 0xFF {
+            return Err(header.error(
+                "version",
+                Error::InvalidHeaderValue("version", header.version),
+            ));
+        }
+        if header.developer > 0xFF {
+            return Err(header.error(
+                "developer",
+                Error::InvalidHeaderValue("developer", header.developer),
+            ));
+        }
+        Ok(header)
     }
-    if let Some(Name::Function(Some(addr), attributes)) = context.names.get("irq_emu") {
-        if attributes.contains(&Attribute::Interrupt) {
-            let bytes = addr.to_le_bytes();
-            code[0x7FFE] = bytes[0];
-            code[0x7FFF] = bytes[1];
+
+    // Where a field was set, if it was
+    fn span(&self, field: &str) -> Option<Span<'a>> {
+        self.spans
+            .iter()
+            .find(|(name, _)| *name == field)
+            .map(|(_, span)| *span)
+    }
+
+    // An error about one of the fields, pointing at where it was set
+    fn error(&self, field: &str, error: Error<'a>) -> LocatedError<'a> {
+        let span = self.span(field);
+        LocatedError { error, span }
+    }
+
+    // Fill in everything up to (but not including) the checksum
+    fn write(&self, header: &mut [u8], rom_size: u32) {
+        header[0..21].copy_from_slice(&[b' '; 21]);
+        header[0..self.title.len()].copy_from_slice(self.title.as_bytes());
+        header[0x15] = self.map.map_mode();
+        if self.sram_size == 0 {
+            header[0x16] = 0x00; // ROM only
+            header[0x18] = 0x00;
         } else {
-            return Err(Error::InvalidInterrupt("irq_emu"));
+            header[0x16] = 0x02; // ROM + RAM + battery
+            header[0x18] = self.sram_size.trailing_zeros() as u8;
         }
+        header[0x17] = rom_size.next_power_of_two().trailing_zeros() as u8;
+        header[0x19] = self.region as u8;
+        header[0x1A] = self.developer as u8;
+        header[0x1B] = self.version as u8;
     }
+}
 
-    // TODO: Calculate a checksum
+fn checksum(image: &[u8]) -> u16 {
+    mirrored_sum(image, image.len().next_power_of_two()) as u16
+}
 
-    Ok(code)
+// Sum `data` as if it were mirrored out to `size` bytes. Images which
+// are not a power of two in size are split into the largest power of
+// two that fits, and the remainder is repeated until it fills the
+// same space again.
+fn mirrored_sum(data: &[u8], size: usize) -> u32 {
+    if data.is_empty() {
+        return 0;
+    }
+    let base = if data.len().is_power_of_two() {
+        data.len()
+    } else {
+        data.len().next_power_of_two() / 2
+    };
+    let sum = data[..base]
+        .iter()
+        .fold(0u32, |sum, byte| sum.wrapping_add(*byte as u32));
+    if base == data.len() {
+        sum.wrapping_mul((size / base) as u32)
+    } else {
+        sum.wrapping_add(mirrored_sum(&data[base..], size - base))
+    }
+}
+
+fn assemble_data<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    data: &'p Data,
+    base: &Path,
+) -> Result<'p> {
+    context.span = Some(Span(data.name));
+    select_bank(context, &data.attributes, data.name)?;
+    let data_addr = context.bank().addr();
+    match &data.contents {
+        DataContents::Table(width, values) => {
+            let (size, max) = match width {
+                Width::Byte => (1, 0xFF),
+                Width::Word => (2, 0xFFFF),
+                Width::Long => (3, 0xFF_FFFF),
+            };
+            let mut bytes = Vec::with_capacity(values.len() * size);
+            for value in values {
+                if *value > max {
+                    return Err(Error::InvalidValue(*value, data.name));
+                }
+                bytes.extend_from_slice(&value.to_le_bytes()[0..size]);
+            }
+            context.bank().push_data("Data", data.name, &bytes)?;
+        }
+        DataContents::Binary(path) => {
+            let bytes =
+                std::fs::read(base.join(path)).map_err(|e| Error::IncludeFailed(path, e.kind()))?;
+            context.bank().push_data("Incbin", data.name, &bytes)?;
+        }
+    }
+    context.names.insert(data.name, Name::Var(data_addr as u32));
+    Ok(())
 }
 
 fn assemble_function<'c, 'p: 'c>(
@@ -187,36 +679,57 @@ fn assemble_function<'c, 'p: 'c>(
     let wide_math = context.wide_math;
     let wide_index = context.wide_index;
 
-    let function_addr = context.bank.code.len() + context.bank.start;
+    context.span = Some(Span(function.name));
+    select_bank(context, &function.body.attributes, function.name)?;
+
+    let function_addr = context.bank().addr();
+    context.jumps.clear();
     context.names.insert(
         function.name,
         Name::Function(Some(function_addr), function.body.attributes.clone()),
     );
 
-    // TODO: Make this way more efficient (maybe a refcell?)
-    let mut code = context.bank.code.clone();
+    let banks = &mut context.banks;
     context.relocations.retain(|relo| {
-        if let Relocation::Function(func, fixup) = relo {
-            if *func == function.name {
-                let addr_bytes = function_addr.to_le_bytes();
-                code[*fixup] = addr_bytes[0];
-                code[*fixup + 1] = addr_bytes[1];
+        let addr_bytes = function_addr.to_le_bytes();
+        match relo {
+            Relocation::Function(func, bank, fixup) if *func == function.name => {
+                banks[*bank].code[*fixup] = addr_bytes[0];
+                banks[*bank].code[*fixup + 1] = addr_bytes[1];
                 false
-            } else {
-                true
             }
-        } else {
-            true
+            Relocation::LongFunction(func, bank, fixup) if *func == function.name => {
+                banks[*bank].code[*fixup] = addr_bytes[0];
+                banks[*bank].code[*fixup + 1] = addr_bytes[1];
+                banks[*bank].code[*fixup + 2] = addr_bytes[2];
+                false
+            }
+            _ => true,
         }
     });
-    context.bank.code = code;
 
-    update_codegen(context, &function.body.attributes, &function.name)?;
+    update_codegen(context, &function.body.attributes, function.name)?;
+    let mut entry_emulation = context.emulation;
+    let mut entry_wide_math = context.wide_math;
+    let mut entry_wide_index = context.wide_index;
 
-    for instruction in &function.body.instructions {
-        assemble_instruction(context, instruction, function.name)?;
+    for statement in &function.body.instructions {
+        assemble_statement(context, statement, function.name);
     }
 
+    // BYTES can leave the modes changed, so put them back for the caller
+    std::mem::swap(&mut entry_emulation, &mut context.emulation);
+    std::mem::swap(&mut entry_wide_math, &mut context.wide_math);
+    std::mem::swap(&mut entry_wide_index, &mut context.wide_index);
+    update_emulation(
+        context,
+        entry_emulation,
+        entry_wide_math,
+        entry_wide_index,
+        function.name,
+    )?;
+    update_mx(context, entry_wide_math, entry_wide_index, function.name)?;
+
     let opcode = if function.body.attributes.contains(&Attribute::Interrupt) {
         0x40 // RTI
     } else if function.body.attributes.contains(&Attribute::Extern) {
@@ -225,7 +738,9 @@ fn assemble_function<'c, 'p: 'c>(
         0x60 // RTS
     };
 
-    context.bank.push_code("Return", function.name, &[opcode])?;
+    context
+        .bank()
+        .push_code("Return", function.name, &[opcode])?;
 
     context.emulation = emulation;
     context.wide_math = wide_math;
@@ -233,6 +748,26 @@ fn assemble_function<'c, 'p: 'c>(
     Ok(())
 }
 
+// Assemble one statement, keeping track of where it is in the source.
+// A statement that fails is recorded and dropped, and assembly goes on
+// with the next one so that every error gets reported.
+fn assemble_statement<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    statement: &'p Statement<'p>,
+    function_name: &'p str,
+) {
+    let outer = context.span.replace(statement.span);
+    let checkpoint = context.checkpoint();
+    if let Err(error) = assemble_instruction(context, &statement.instruction, function_name) {
+        // Errors from statements nested inside this one still count
+        let inner = context.errors.split_off(checkpoint.errors);
+        context.rewind(checkpoint);
+        context.errors.extend(inner);
+        context.fail(error);
+    }
+    context.span = outer;
+}
+
 fn assemble_instruction<'c, 'p: 'c>(
     context: &'c mut Context<'p>,
     instruction: &'p Instruction,
@@ -254,34 +789,11 @@ fn assemble_instruction<'c, 'p: 'c>(
                         let bytes = value.to_le_bytes();
                         let instruction = [0xA9, bytes[0]]; // LDA imm
                         context
-                            .bank
+                            .bank()
                             .push_code("Load A", function_name, &instruction)
                     }
                 }
             }
-            (Operand::Register(Register::A), Operand::Variable(var)) => {
-                if context.wide_math {
-                    Err(Error::InvalidRegister(
-                        Register::A,
-                        Attribute::WideMath,
-                        function_name,
-                    ))
-                } else {
-                    if let Some(Name::Var(addr)) = context.names.get(var) {
-                        if *addr > 0xFFFF {
-                            Err(Error::InvalidAddress(*addr, function_name))
-                        } else {
-                            let bytes = addr.to_le_bytes();
-                            let instruction = [0xAD, bytes[0], bytes[1]]; // LDA abs
-                            context
-                                .bank
-                                .push_code("Load A", function_name, &instruction)
-                        }
-                    } else {
-                        Err(Error::UnknownVariable(var, function_name))
-                    }
-                }
-            }
             (Operand::Register(Register::C), Operand::Immediate(value)) => {
                 if context.wide_math {
                     if *value > 0xFFFF {
@@ -290,7 +802,7 @@ fn assemble_instruction<'c, 'p: 'c>(
                         let bytes = value.to_le_bytes();
                         let instruction = [0xA9, bytes[0], bytes[1]]; // LDA imm
                         context
-                            .bank
+                            .bank()
                             .push_code("Load C", function_name, &instruction)
                     }
                 } else {
@@ -301,141 +813,192 @@ fn assemble_instruction<'c, 'p: 'c>(
                     ))
                 }
             }
-            (Operand::Register(Register::C), Operand::Variable(var)) => {
-                if context.wide_math {
-                    if let Some(Name::Var(addr)) = context.names.get(var) {
-                        if *addr > 0xFFFF {
-                            Err(Error::InvalidAddress(*addr, function_name))
-                        } else {
-                            let bytes = addr.to_le_bytes();
-                            let instruction = [0xAD, bytes[0], bytes[1]]; // LDA abs
-                            context
-                                .bank
-                                .push_code("Load A", function_name, &instruction)
-                        }
-                    } else {
-                        Err(Error::UnknownVariable(var, function_name))
-                    }
-                } else {
-                    Err(Error::InvalidRegister(
-                        Register::C,
-                        Attribute::NarrowMath,
-                        function_name,
-                    ))
-                }
+            (Operand::Register(to), Operand::Register(from)) => {
+                let code = transfer(context, to, from, function_name)?
+                    .ok_or(Error::BadAssignment(lhs, rhs, function_name))?;
+                context.bank().push_code("Transfer", function_name, code)
             }
-            (Operand::Register(Register::D), Operand::Register(Register::C)) => context
-                .bank
-                .push_code("Transfer C to D", function_name, &[0x5B]),
-            (Operand::Register(Register::S), Operand::Register(Register::C)) => context
-                .bank
-                .push_code("Transfer C to S", function_name, &[0x1B]),
-            (Operand::Register(Register::X), Operand::Immediate(value)) => {
+            (Operand::Register(reg @ Register::X), Operand::Immediate(value))
+            | (Operand::Register(reg @ Register::Y), Operand::Immediate(value)) => {
+                let opcode = if *reg == Register::X {
+                    0xA2 // LDX imm
+                } else {
+                    0xA0 // LDY imm
+                };
                 if context.wide_index {
                     if *value > 0xFFFF {
                         Err(Error::InvalidValue(*value, function_name))
                     } else {
                         let bytes = value.to_le_bytes();
-                        let instruction = [0xA2, bytes[0], bytes[1]]; // LDX imm
+                        let instruction = [opcode, bytes[0], bytes[1]];
                         context
-                            .bank
-                            .push_code("Load X imm", function_name, &instruction)
+                            .bank()
+                            .push_code("Load index imm", function_name, &instruction)
                     }
                 } else {
                     if *value > 0xFF {
                         Err(Error::InvalidValue(*value, function_name))
                     } else {
                         let bytes = value.to_le_bytes();
-                        let instruction = [0xA2, bytes[0]]; // LX imm
+                        let instruction = [opcode, bytes[0]];
                         context
-                            .bank
-                            .push_code("Load X imm", function_name, &instruction)
+                            .bank()
+                            .push_code("Load index imm", function_name, &instruction)
                     }
                 }
             }
-            (Operand::Register(Register::X), Operand::Variable(var)) => {
-                if let Some(Name::Var(addr)) = context.names.get(var) {
-                    if *addr > 0xFFFF {
-                        Err(Error::InvalidAddress(*addr, function_name))
-                    } else {
-                        let bytes = addr.to_le_bytes();
-                        let instruction = [0xAE, bytes[0], bytes[1]]; // LDX abs
-                        context
-                            .bank
-                            .push_code("Load X abs", function_name, &instruction)
+            (mem, Operand::Immediate(0)) => {
+                let (mode, addr) = memory_operand(context, mem, function_name)?
+                    .ok_or(Error::BadAssignment(lhs, rhs, function_name))?;
+                let opcode = match mode {
+                    Mode::Absolute => 0x9C,
+                    Mode::AbsoluteX => 0x9E,
+                    Mode::Direct => 0x64,
+                    Mode::DirectX => 0x74,
+                    _ => {
+                        let error = Error::BadAssignment(lhs, rhs, function_name);
+                        return Err(far_operand_or(mode, lhs, error, function_name));
                     }
-                } else {
-                    Err(Error::UnknownVariable(var, function_name))
-                }
+                }; // STZ
+                push_memory(context, "Store Zero", function_name, opcode, mode, addr)
             }
-            (Operand::Absolute(addr), Operand::Register(Register::A)) => {
-                if *addr > 0xFFFF {
-                    Err(Error::InvalidAddress(*addr, function_name))
-                } else {
-                    let bytes = addr.to_le_bytes();
-                    let instruction = [0x8D, bytes[0], bytes[1]];
-                    context
-                        .bank
-                        .push_code("Store A", function_name, &instruction)
-                }
-            }
-            (Operand::Absolute(addr), Operand::Immediate(0)) => {
-                if *addr > 0xFFFF {
-                    Err(Error::InvalidAddress(*addr, function_name))
-                } else {
-                    let bytes = addr.to_le_bytes();
-                    let instruction = [0x9C, bytes[0], bytes[1]];
-                    context
-                        .bank
-                        .push_code("Store Zero", function_name, &instruction)
-                }
+            (Operand::Register(reg), mem) => {
+                let (mode, addr) = memory_operand(context, mem, function_name)?
+                    .ok_or(Error::BadAssignment(lhs, rhs, function_name))?;
+                check_width(context, reg, function_name)?;
+                let opcode = match (reg, mode) {
+                    (Register::A, _) | (Register::C, _) => mode
+                        .accumulator_opcode(0xA0) // LDA
+                        .ok_or(Error::BadAssignment(lhs, rhs, function_name))?,
+                    (Register::X, Mode::Absolute) => 0xAE,
+                    (Register::X, Mode::AbsoluteY) => 0xBE,
+                    (Register::X, Mode::Direct) => 0xA6,
+                    (Register::X, Mode::DirectY) => 0xB6,
+                    (Register::Y, Mode::Absolute) => 0xAC,
+                    (Register::Y, Mode::AbsoluteX) => 0xBC,
+                    (Register::Y, Mode::Direct) => 0xA4,
+                    (Register::Y, Mode::DirectX) => 0xB4,
+                    _ => {
+                        let error = Error::BadAssignment(lhs, rhs, function_name);
+                        return Err(far_operand_or(mode, rhs, error, function_name));
+                    }
+                };
+                push_memory(context, "Load", function_name, opcode, mode, addr)
             }
-            (Operand::Variable(var), Operand::Register(Register::A)) => {
-                if let Some(Name::Var(addr)) = context.names.get(var) {
-                    if *addr > 0xFFFF {
-                        Err(Error::InvalidAddress(*addr, function_name))
-                    } else {
-                        let bytes = addr.to_le_bytes();
-                        let instruction = [0x8D, bytes[0], bytes[1]]; // STA abs
-                        context
-                            .bank
-                            .push_code("Store A", function_name, &instruction)
+            (mem, Operand::Register(reg)) => {
+                let (mode, addr) = memory_operand(context, mem, function_name)?
+                    .ok_or(Error::BadAssignment(lhs, rhs, function_name))?;
+                check_width(context, reg, function_name)?;
+                let opcode = match (reg, mode) {
+                    (Register::A, _) | (Register::C, _) => mode
+                        .accumulator_opcode(0x80) // STA
+                        .ok_or(Error::BadAssignment(lhs, rhs, function_name))?,
+                    (Register::X, Mode::Absolute) => 0x8E,
+                    (Register::X, Mode::Direct) => 0x86,
+                    (Register::X, Mode::DirectY) => 0x96,
+                    (Register::Y, Mode::Absolute) => 0x8C,
+                    (Register::Y, Mode::Direct) => 0x84,
+                    (Register::Y, Mode::DirectX) => 0x94,
+                    _ => {
+                        let error = Error::BadAssignment(lhs, rhs, function_name);
+                        return Err(far_operand_or(mode, lhs, error, function_name));
                     }
-                } else {
-                    Err(Error::UnknownVariable(var, function_name))
-                }
+                };
+                push_memory(context, "Store", function_name, opcode, mode, addr)
             }
-            (Operand::Variable(var), Operand::Register(Register::X)) => {
-                if let Some(Name::Var(addr)) = context.names.get(var) {
-                    if *addr > 0xFFFF {
-                        Err(Error::InvalidAddress(*addr, function_name))
-                    } else {
-                        let bytes = addr.to_le_bytes();
-                        let instruction = [0x8E, bytes[0], bytes[1]]; // STX abs
-                        context
-                            .bank
-                            .push_code("Store X", function_name, &instruction)
+            (l, r) => Err(Error::BadAssignment(l, r, function_name)),
+        },
+        Instruction::AddAssign(l, r) => {
+            context
+                .bank()
+                .push_code("Clear Carry", function_name, &[0x18])?; // CLC
+            let error = Error::BadAddAssignment(l, r, function_name);
+            assemble_accumulator_op(context, "Add", 0x60, l, r, error, function_name)
+            // ADC
+        }
+        Instruction::AddCarryAssign(l, r) => {
+            let error = Error::BadAddAssignment(l, r, function_name);
+            assemble_accumulator_op(context, "Add", 0x60, l, r, error, function_name)
+            // ADC
+        }
+        Instruction::SubAssign(l, r) => {
+            context
+                .bank()
+                .push_code("Set Carry", function_name, &[0x38])?; // SEC
+            let error = Error::BadSubAssignment(l, r, function_name);
+            assemble_accumulator_op(context, "Subtract", 0xE0, l, r, error, function_name)
+            // SBC
+        }
+        Instruction::SubCarryAssign(l, r) => {
+            let error = Error::BadSubAssignment(l, r, function_name);
+            assemble_accumulator_op(context, "Subtract", 0xE0, l, r, error, function_name)
+            // SBC
+        }
+        Instruction::Increment(operand) => match operand {
+            Operand::Register(reg @ Register::A) | Operand::Register(reg @ Register::C) => {
+                check_width(context, reg, function_name)?;
+                context
+                    .bank()
+                    .push_code("Increment", function_name, &[0x1A]) // INC A
+            }
+            Operand::Register(Register::X) => {
+                context
+                    .bank()
+                    .push_code("Increment", function_name, &[0xE8]) // INX
+            }
+            Operand::Register(Register::Y) => {
+                context
+                    .bank()
+                    .push_code("Increment", function_name, &[0xC8]) // INY
+            }
+            _ => {
+                let (mode, addr) = memory_operand(context, operand, function_name)?
+                    .ok_or(Error::BadIncrement(operand, function_name))?;
+                let opcode = match mode {
+                    Mode::Absolute => 0xEE,
+                    Mode::AbsoluteX => 0xFE,
+                    Mode::Direct => 0xE6,
+                    Mode::DirectX => 0xF6,
+                    _ => {
+                        let error = Error::BadIncrement(operand, function_name);
+                        return Err(far_operand_or(mode, operand, error, function_name));
                     }
-                } else {
-                    Err(Error::UnknownVariable(var, function_name))
-                }
+                }; // INC
+                push_memory(context, "Increment", function_name, opcode, mode, addr)
             }
-            (Operand::Variable(var), Operand::Immediate(0)) => {
-                if let Some(Name::Var(addr)) = context.names.get(var) {
-                    if *addr > 0xFFFF {
-                        Err(Error::InvalidAddress(*addr, function_name))
-                    } else {
-                        let bytes = addr.to_le_bytes();
-                        let instruction = [0x9C, bytes[0], bytes[1]]; // STZ abs
-                        context
-                            .bank
-                            .push_code("Store Zero", function_name, &instruction)
+        },
+        Instruction::Decrement(operand) => match operand {
+            Operand::Register(reg @ Register::A) | Operand::Register(reg @ Register::C) => {
+                check_width(context, reg, function_name)?;
+                context
+                    .bank()
+                    .push_code("Decrement", function_name, &[0x3A]) // DEC A
+            }
+            Operand::Register(Register::X) => {
+                context
+                    .bank()
+                    .push_code("Decrement", function_name, &[0xCA]) // DEX
+            }
+            Operand::Register(Register::Y) => {
+                context
+                    .bank()
+                    .push_code("Decrement", function_name, &[0x88]) // DEY
+            }
+            _ => {
+                let (mode, addr) = memory_operand(context, operand, function_name)?
+                    .ok_or(Error::BadDecrement(operand, function_name))?;
+                let opcode = match mode {
+                    Mode::Absolute => 0xCE,
+                    Mode::AbsoluteX => 0xDE,
+                    Mode::Direct => 0xC6,
+                    Mode::DirectX => 0xD6,
+                    _ => {
+                        let error = Error::BadDecrement(operand, function_name);
+                        return Err(far_operand_or(mode, operand, error, function_name));
                     }
-                } else {
-                    Err(Error::UnknownVariable(var, function_name))
-                }
+                }; // DEC
+                push_memory(context, "Decrement", function_name, opcode, mode, addr)
             }
-            (l, r) => Err(Error::BadAssignment(l, r, function_name)),
         },
         Instruction::AndAssign(l, r) => {
             match (l, r) {
@@ -453,7 +1016,7 @@ fn assemble_instruction<'c, 'p: 'c>(
                             let bytes = value.to_le_bytes();
                             let instruction = [0x29, bytes[0]]; // AND imm
                             context
-                                .bank
+                                .bank()
                                 .push_code("And A imm", function_name, &instruction)
                         }
                     }
@@ -477,7 +1040,7 @@ fn assemble_instruction<'c, 'p: 'c>(
                             let bytes = value.to_le_bytes();
                             let instruction = [0x09, bytes[0]]; // ORA imm
                             context
-                                .bank
+                                .bank()
                                 .push_code("Or A imm", function_name, &instruction)
                         }
                     }
@@ -485,23 +1048,24 @@ fn assemble_instruction<'c, 'p: 'c>(
                 _ => Err(Error::BadOrAssignment(l, r, function_name)),
             }
         }
-        Instruction::Block(block) => {
-            let mut emulation = context.emulation;
-            let mut wide_math = context.wide_math;
-            let mut wide_index = context.wide_index;
-            update_codegen(context, &block.attributes, function_name)?;
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-            for instruction in &block.instructions {
-                assemble_instruction(context, instruction, function_name)?;
-            }
-            std::mem::swap(&mut emulation, &mut context.emulation);
-            std::mem::swap(&mut wide_math, &mut context.wide_math);
-            std::mem::swap(&mut wide_index, &mut context.wide_index);
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-            Ok(())
+        Instruction::XorAssign(l, r) => {
+            let error = Error::BadXorAssignment(l, r, function_name);
+            assemble_accumulator_op(context, "Xor", 0x40, l, r, error, function_name)
+            // EOR
+        }
+        Instruction::ShiftLeftAssign(l, r) => {
+            assemble_shift(context, "Shift Left", 0x00, l, r, function_name) // ASL
+        }
+        Instruction::ShiftRightAssign(l, r) => {
+            assemble_shift(context, "Shift Right", 0x40, l, r, function_name) // LSR
+        }
+        Instruction::RotateLeftAssign(l, r) => {
+            assemble_shift(context, "Rotate Left", 0x20, l, r, function_name) // ROL
         }
+        Instruction::RotateRightAssign(l, r) => {
+            assemble_shift(context, "Rotate Right", 0x60, l, r, function_name) // ROR
+        }
+        Instruction::Block(block) => assemble_block(context, block, function_name),
         Instruction::Call(target) => {
             let target_fun = context.names.get(target).cloned();
             if let Some(Name::Function(addr, attributes)) = target_fun {
@@ -509,108 +1073,135 @@ fn assemble_instruction<'c, 'p: 'c>(
                 let mut wide_math = context.wide_math;
                 let mut wide_index = context.wide_index;
                 update_codegen(context, &attributes, function_name)?;
-                update_emulation(context, emulation, function_name)?;
+                update_emulation(context, emulation, wide_math, wide_index, function_name)?;
                 update_mx(context, wide_math, wide_index, function_name)?;
-                let addr = addr.unwrap_or_else(|| {
-                    context
-                        .relocations
-                        .push(Relocation::Function(target, context.bank.code.len() + 1));
-                    0
-                });
-                let bytes = addr.to_le_bytes();
+                let fixup = context.bank().code.len() + 1;
                 if attributes.contains(&Attribute::Extern) {
-                    unimplemented!("Calling extern functions")
+                    let addr = addr.unwrap_or_else(|| {
+                        context.relocations.push(Relocation::LongFunction(
+                            target,
+                            context.current_bank,
+                            fixup,
+                        ));
+                        0
+                    });
+                    let bytes = addr.to_le_bytes();
+                    context.bank().push_code(
+                        "Call Long",
+                        function_name,
+                        &[0x22, bytes[0], bytes[1], bytes[2]],
+                    )?;
                 } else {
+                    // JSR can only reach functions in the caller's bank
+                    if attribute_bank(&attributes) as usize != context.current_bank {
+                        return Err(Error::FarCall(target, function_name));
+                    }
+                    let addr = addr.unwrap_or_else(|| {
+                        context.relocations.push(Relocation::Function(
+                            target,
+                            context.current_bank,
+                            fixup,
+                        ));
+                        0
+                    });
+                    let bytes = addr.to_le_bytes();
                     context
-                        .bank
+                        .bank()
                         .push_code("Call", function_name, &[0x20, bytes[0], bytes[1]])?;
                 }
                 std::mem::swap(&mut emulation, &mut context.emulation);
                 std::mem::swap(&mut wide_math, &mut context.wide_math);
                 std::mem::swap(&mut wide_index, &mut context.wide_index);
-                update_emulation(context, emulation, function_name)?;
+                update_emulation(context, emulation, wide_math, wide_index, function_name)?;
                 update_mx(context, wide_math, wide_index, function_name)?;
                 Ok(())
             } else {
                 Err(Error::UnknownFunction(target, function_name))
             }
         }
-        Instruction::If(block, cond) => {
-            assemble_conditional(context, cond, None, true, function_name)?;
-            let block_start = context.bank.code.len();
-            let mut emulation = context.emulation;
-            let mut wide_math = context.wide_math;
-            let mut wide_index = context.wide_index;
-            update_codegen(context, &block.attributes, function_name)?;
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-            for instruction in &block.instructions {
-                assemble_instruction(context, instruction, function_name)?;
-            }
-            std::mem::swap(&mut emulation, &mut context.emulation);
-            std::mem::swap(&mut wide_math, &mut context.wide_math);
-            std::mem::swap(&mut wide_index, &mut context.wide_index);
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-
-            let block_len = context.bank.code.len() - block_start;
-            if block_len > 127 {
-                return Err(Error::IfTooLong(function_name));
-            }
-
-            let fixup = block_start - 1;
-            context.bank.code[fixup] = block_len as u8;
-            Ok(())
+        Instruction::If(block, cond, else_block) => {
+            assemble_if(context, block, cond, else_block, function_name)
         }
         Instruction::Loop(block, cond) => {
-            let mut emulation = context.emulation;
-            let mut wide_math = context.wide_math;
-            let mut wide_index = context.wide_index;
-            update_codegen(context, &block.attributes, function_name)?;
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-            let loop_start = context.bank.code.len();
-            for instruction in &block.instructions {
-                assemble_instruction(context, instruction, function_name)?;
-            }
-            context.relocations.retain(|relo| {
-                if let Relocation::Break(_) = relo {
-                    unimplemented!("Break relocations");
-                } else {
-                    true
-                }
-            });
-            if let Some(cond) = cond {
-                assemble_conditional(context, cond, Some(loop_start), false, function_name)?;
-            } else {
-                let loop_length = context.bank.code.len() - loop_start;
-                if loop_length <= 126 {
-                    // Short loop, use a BRA
-                    let offset = (!(loop_length as u8 + 2)) + 1;
-                    context
-                        .bank
-                        .push_code("Loop", function_name, &[0x80, offset])?;
-                } else {
-                    // Long loop, must use a JMP
-                    let bytes = (loop_start + context.bank.start).to_le_bytes();
-                    context
-                        .bank
-                        .push_code("Loop", function_name, &[0x4C, bytes[0], bytes[1]])?;
-                }
-            }
-            std::mem::swap(&mut emulation, &mut context.emulation);
-            std::mem::swap(&mut wide_math, &mut context.wide_math);
-            std::mem::swap(&mut wide_index, &mut context.wide_index);
-            update_emulation(context, emulation, function_name)?;
-            update_mx(context, wide_math, wide_index, function_name)?;
-            Ok(())
+            let test = match cond {
+                Some(cond) => LoopTest::DoWhile(cond),
+                None => LoopTest::Forever,
+            };
+            assemble_loop(context, block, test, function_name)
         }
-        Instruction::Cli => context.bank.push_code("Cli", function_name, &[0x58]),
-        Instruction::Sei => context.bank.push_code("Sei", function_name, &[0x78]),
-        Instruction::Push(reg) => match reg {
-            Operand::Register(Register::A) => {
-                if !context.wide_math {
-                    context.bank.push_code("PHA", function_name, &[0x48])
+        Instruction::While(block, cond) => {
+            assemble_loop(context, block, LoopTest::While(cond), function_name)
+        }
+        Instruction::For(block, reg, from, to) => {
+            let test = LoopTest::For(reg, *from, *to);
+            assemble_loop(context, block, test, function_name)
+        }
+        Instruction::Break => {
+            let target = match context.loops.last() {
+                Some(target) => target.clone(),
+                None => return Err(Error::BreakOutsideLoop(function_name)),
+            };
+            switch_mode(
+                context,
+                target.exit_emulation,
+                target.exit_wide_math,
+                target.exit_wide_index,
+                function_name,
+            )?;
+            // Pointed at the end of the loop once it's known, and
+            // widened into a JMP if that's too far
+            let fixup = context.bank().code.len() + 1;
+            context.relocations.push(Relocation::Break(fixup));
+            context
+                .bank()
+                .push_code("Break", function_name, &[0x80, 0x00]) // BRA
+        }
+        Instruction::Continue => {
+            let target = match context.loops.last() {
+                Some(target) => target.clone(),
+                None => return Err(Error::ContinueOutsideLoop(function_name)),
+            };
+            switch_mode(
+                context,
+                target.emulation,
+                target.wide_math,
+                target.wide_index,
+                function_name,
+            )?;
+            context
+                .bank()
+                .push_code("Continue", function_name, &[0x80, 0x00])?; // BRA
+            let site = context.bank().code.len() - 2;
+            if target.conditional {
+                // The condition is at the bottom of the loop, so we
+                // don't know where it is yet
+                context.relocations.push(Relocation::Continue(site + 1));
+                Ok(())
+            } else {
+                jump(context, site, target.start, function_name)
+            }
+        }
+        Instruction::Cli => context.bank().push_code("Cli", function_name, &[0x58]),
+        Instruction::Sei => context.bank().push_code("Sei", function_name, &[0x78]),
+        Instruction::Swap => context.bank().push_code("Swap", function_name, &[0xEB]), // XBA
+        Instruction::Bytes(values, attributes) => {
+            let mut bytes = vec![];
+            for value in values {
+                if *value > 0xFF {
+                    return Err(Error::InvalidValue(*value, function_name));
+                }
+                bytes.push(*value as u8);
+            }
+            // Stored as data, since nothing says the bytes line up with
+            // whole instructions
+            context.bank().push_data("Bytes", function_name, &bytes)?;
+            // The bytes are trusted to have left the modes as declared
+            update_codegen(context, attributes, function_name)
+        }
+        Instruction::Push(reg) => match reg {
+            Operand::Register(Register::A) => {
+                if !context.wide_math {
+                    context.bank().push_code("PHA", function_name, &[0x48])
                 } else {
                     Err(Error::InvalidRegister(
                         Register::A,
@@ -621,7 +1212,7 @@ fn assemble_instruction<'c, 'p: 'c>(
             }
             Operand::Register(Register::C) => {
                 if context.wide_math {
-                    context.bank.push_code("PHA", function_name, &[0x48])
+                    context.bank().push_code("PHA", function_name, &[0x48])
                 } else {
                     Err(Error::InvalidRegister(
                         Register::C,
@@ -630,14 +1221,18 @@ fn assemble_instruction<'c, 'p: 'c>(
                     ))
                 }
             }
-            Operand::Register(Register::X) => context.bank.push_code("PHX", function_name, &[0xDA]),
-            Operand::Register(Register::Y) => context.bank.push_code("PHY", function_name, &[0x5A]),
+            Operand::Register(Register::X) => {
+                context.bank().push_code("PHX", function_name, &[0xDA])
+            }
+            Operand::Register(Register::Y) => {
+                context.bank().push_code("PHY", function_name, &[0x5A])
+            }
             _ => Err(Error::BadPush(reg, function_name)),
         },
         Instruction::Pop(reg) => match reg {
             Operand::Register(Register::A) => {
                 if !context.wide_math {
-                    context.bank.push_code("PLA", function_name, &[0x68])
+                    context.bank().push_code("PLA", function_name, &[0x68])
                 } else {
                     Err(Error::InvalidRegister(
                         Register::A,
@@ -648,7 +1243,7 @@ fn assemble_instruction<'c, 'p: 'c>(
             }
             Operand::Register(Register::C) => {
                 if context.wide_math {
-                    context.bank.push_code("PLA", function_name, &[0x68])
+                    context.bank().push_code("PLA", function_name, &[0x68])
                 } else {
                     Err(Error::InvalidRegister(
                         Register::C,
@@ -657,13 +1252,716 @@ fn assemble_instruction<'c, 'p: 'c>(
                     ))
                 }
             }
-            Operand::Register(Register::X) => context.bank.push_code("PLX", function_name, &[0xFA]),
-            Operand::Register(Register::Y) => context.bank.push_code("PLY", function_name, &[0x7A]),
+            Operand::Register(Register::X) => {
+                context.bank().push_code("PLX", function_name, &[0xFA])
+            }
+            Operand::Register(Register::Y) => {
+                context.bank().push_code("PLY", function_name, &[0x7A])
+            }
             _ => Err(Error::BadPop(reg, function_name)),
         },
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Mode {
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Long,
+    LongX,
+    Direct,
+    DirectX,
+    DirectY,
+    DirectIndirect,
+    DirectIndirectY,
+    DirectIndirectLong,
+    DirectIndirectLongY,
+    Stack,
+    StackIndirectY,
+}
+
+impl Mode {
+    // ORA, AND, EOR, ADC, STA, LDA, CMP and SBC all share one opcode
+    // layout, with the instruction in the top three bits
+    fn accumulator_opcode(self, base: u8) -> Option<u8> {
+        let mode = match self {
+            Mode::Stack => 0x03,
+            Mode::Direct => 0x05,
+            Mode::DirectIndirectLong => 0x07,
+            Mode::Absolute => 0x0D,
+            Mode::Long => 0x0F,
+            Mode::DirectIndirectY => 0x11,
+            Mode::DirectIndirect => 0x12,
+            Mode::StackIndirectY => 0x13,
+            Mode::DirectX => 0x15,
+            Mode::DirectIndirectLongY => 0x17,
+            Mode::AbsoluteY => 0x19,
+            Mode::AbsoluteX => 0x1D,
+            Mode::LongX => 0x1F,
+            Mode::DirectY => return None,
+        };
+        Some(base | mode)
+    }
+
+    fn operand_len(self) -> usize {
+        match self {
+            Mode::Absolute | Mode::AbsoluteX | Mode::AbsoluteY => 2,
+            Mode::Long | Mode::LongX => 3,
+            _ => 1,
+        }
+    }
+}
+
+// Work out how an operand addresses memory. Registers and immediates
+// aren't memory, so they give back `None`.
+fn memory_operand<'c, 'p: 'c>(
+    context: &'c Context<'p>,
+    operand: &'p Operand<'p>,
+    function_name: &'p str,
+) -> std::result::Result<Option<(Mode, u32)>, Error<'p>> {
+    let bad = || Error::BadOperand(operand, function_name);
+    let resolved = match operand {
+        Operand::Immediate(_) | Operand::Register(_) => return Ok(None),
+        Operand::Absolute(addr) => absolute(*addr, function_name)?,
+        Operand::Variable(var) => {
+            if let Some(Name::Var(addr)) = context.names.get(var) {
+                absolute(*addr, function_name)?
+            } else {
+                return Err(Error::UnknownVariable(var, function_name));
+            }
+        }
+        Operand::Direct(offset) => (Mode::Direct, byte(*offset, function_name)?),
+        Operand::Stack(offset) => (Mode::Stack, byte(*offset, function_name)?),
+        Operand::Indirect(inner) => match &**inner {
+            Operand::Direct(offset) => (Mode::DirectIndirect, byte(*offset, function_name)?),
+            _ => return Err(bad()),
+        },
+        Operand::IndirectLong(inner) => match &**inner {
+            Operand::Direct(offset) => (Mode::DirectIndirectLong, byte(*offset, function_name)?),
+            _ => return Err(bad()),
+        },
+        Operand::Indexed(inner, index) => {
+            // Stack-relative indirect only exists as the indexed form
+            if let (Operand::Indirect(stack), Register::Y) = (&**inner, index) {
+                if let Operand::Stack(offset) = &**stack {
+                    return Ok(Some((Mode::StackIndirectY, byte(*offset, function_name)?)));
+                }
+            }
+            let (mode, addr) = memory_operand(context, inner, function_name)?.ok_or_else(bad)?;
+            let mode = match (mode, index) {
+                (Mode::Absolute, Register::X) => Mode::AbsoluteX,
+                (Mode::Absolute, Register::Y) => Mode::AbsoluteY,
+                (Mode::Long, Register::X) => Mode::LongX,
+                (Mode::Direct, Register::X) => Mode::DirectX,
+                (Mode::Direct, Register::Y) => Mode::DirectY,
+                (Mode::DirectIndirect, Register::Y) => Mode::DirectIndirectY,
+                (Mode::DirectIndirectLong, Register::Y) => Mode::DirectIndirectLongY,
+                _ => return Err(far_operand_or(mode, operand, bad(), function_name)),
+            };
+            (mode, addr)
+        }
+    };
+    Ok(Some(resolved))
+}
+
+// One of the accumulator instructions, applied to A or C with an
+// immediate or memory operand
+fn assemble_accumulator_op<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    operation: &'static str,
+    base: u8,
+    lhs: &'p Operand<'p>,
+    rhs: &'p Operand<'p>,
+    error: Error<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    let reg = match lhs {
+        Operand::Register(reg @ Register::A) | Operand::Register(reg @ Register::C) => reg,
+        _ => return Err(error),
+    };
+    check_width(context, reg, function_name)?;
+    if let Operand::Immediate(value) = rhs {
+        let bytes = value.to_le_bytes();
+        let opcode = base | 0x09;
+        if context.wide_math {
+            if *value > 0xFFFF {
+                return Err(Error::InvalidValue(*value, function_name));
+            }
+            context
+                .bank()
+                .push_code(operation, function_name, &[opcode, bytes[0], bytes[1]])
+        } else {
+            if *value > 0xFF {
+                return Err(Error::InvalidValue(*value, function_name));
+            }
+            context
+                .bank()
+                .push_code(operation, function_name, &[opcode, bytes[0]])
+        }
+    } else {
+        let (mode, addr) = match memory_operand(context, rhs, function_name)? {
+            Some(operand) => operand,
+            None => return Err(error),
+        };
+        let opcode = match mode.accumulator_opcode(base) {
+            Some(opcode) => opcode,
+            None => return Err(error),
+        };
+        push_memory(context, operation, function_name, opcode, mode, addr)
+    }
+}
+
+// ASL, ROL, LSR and ROR on A, C or memory. Counts above one are
+// unrolled into repeated instructions.
+fn assemble_shift<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    operation: &'static str,
+    base: u8,
+    lhs: &'p Operand<'p>,
+    rhs: &'p Operand<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    let count = match rhs {
+        Operand::Immediate(count) => *count,
+        _ => return Err(Error::BadShift(lhs, rhs, function_name)),
+    };
+    let max = if context.wide_math { 16 } else { 8 };
+    if count == 0 || count > max {
+        return Err(Error::InvalidValue(count, function_name));
+    }
+    let instruction = match lhs {
+        Operand::Register(reg @ Register::A) | Operand::Register(reg @ Register::C) => {
+            check_width(context, reg, function_name)?;
+            vec![base | 0x0A]
+        }
+        _ => {
+            let (mode, addr) = memory_operand(context, lhs, function_name)?
+                .ok_or(Error::BadShift(lhs, rhs, function_name))?;
+            let opcode = match mode {
+                Mode::Direct => base | 0x06,
+                Mode::Absolute => base | 0x0E,
+                Mode::DirectX => base | 0x16,
+                Mode::AbsoluteX => base | 0x1E,
+                _ => {
+                    let error = Error::BadShift(lhs, rhs, function_name);
+                    return Err(far_operand_or(mode, lhs, error, function_name));
+                }
+            };
+            let mut instruction = vec![opcode];
+            instruction.extend_from_slice(&addr.to_le_bytes()[0..mode.operand_len()]);
+            instruction
+        }
+    };
+    for _ in 0..count {
+        context
+            .bank()
+            .push_code(operation, function_name, &instruction)?;
+    }
+    Ok(())
+}
+
+// Only the accumulator instructions have long forms, so nothing else
+// can reach an operand outside bank 0. Explain that if it's why the
+// operand was rejected, or fall back to the given error.
+fn far_operand_or<'p>(
+    mode: Mode,
+    operand: &'p Operand<'p>,
+    error: Error<'p>,
+    function_name: &'p str,
+) -> Error<'p> {
+    match mode {
+        Mode::Long | Mode::LongX => Error::FarOperand(operand, function_name),
+        _ => error,
+    }
+}
+
+fn absolute<'p>(addr: u32, function_name: &'p str) -> std::result::Result<(Mode, u32), Error<'p>> {
+    if addr > 0xFF_FFFF {
+        Err(Error::InvalidAddress(addr, function_name))
+    } else if addr > 0xFFFF {
+        Ok((Mode::Long, addr))
+    } else {
+        Ok((Mode::Absolute, addr))
+    }
+}
+
+fn byte<'p>(offset: u32, function_name: &'p str) -> std::result::Result<u32, Error<'p>> {
+    if offset > 0xFF {
+        Err(Error::InvalidAddress(offset, function_name))
+    } else {
+        Ok(offset)
+    }
+}
+
+fn push_memory<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    operation: &'static str,
+    function_name: &'p str,
+    opcode: u8,
+    mode: Mode,
+    addr: u32,
+) -> Result<'p> {
+    let bytes = addr.to_le_bytes();
+    let mut instruction = vec![opcode];
+    instruction.extend_from_slice(&bytes[0..mode.operand_len()]);
+    context
+        .bank()
+        .push_code(operation, function_name, &instruction)
+}
+
+// A is only usable with narrow math, and C only with wide math
+fn check_width<'c, 'p: 'c>(
+    context: &'c Context<'p>,
+    register: &Register,
+    function_name: &'p str,
+) -> Result<'p> {
+    match register {
+        Register::A if context.wide_math => Err(Error::InvalidRegister(
+            Register::A,
+            Attribute::WideMath,
+            function_name,
+        )),
+        Register::C if !context.wide_math => Err(Error::InvalidRegister(
+            Register::C,
+            Attribute::NarrowMath,
+            function_name,
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The code to copy one register into another, if there's a way to.
+// Transfers between the accumulator and an index register are as wide
+// as the index registers, so the two have to agree. The bank registers
+// can only be reached through the stack.
+fn transfer<'c, 'p: 'c>(
+    context: &'c Context<'p>,
+    to: &Register,
+    from: &Register,
+    function_name: &'p str,
+) -> std::result::Result<Option<&'static [u8]>, Error<'p>> {
+    let code: &[u8] = match (to, from) {
+        (Register::D, Register::C) => &[0x5B], // TCD
+        (Register::S, Register::C) => &[0x1B], // TCS
+        (Register::C, Register::D) => &[0x7B], // TDC
+        (Register::C, Register::S) => &[0x3B], // TSC
+        (Register::Y, Register::X) => &[0x9B], // TXY
+        (Register::X, Register::Y) => &[0xBB], // TYX
+        (Register::X, Register::S) | (Register::S, Register::X) => {
+            // Emulation mode keeps the stack in page 1, so only the
+            // low byte moves
+            if !context.emulation && !context.wide_index {
+                return Err(Error::InvalidRegister(
+                    Register::S,
+                    Attribute::NarrowIndex,
+                    function_name,
+                ));
+            }
+            match to {
+                Register::X => &[0xBA], // TSX
+                _ => &[0x9A],           // TXS
+            }
+        }
+        (index @ Register::X, acc @ Register::A)
+        | (index @ Register::X, acc @ Register::C)
+        | (index @ Register::Y, acc @ Register::A)
+        | (index @ Register::Y, acc @ Register::C)
+        | (acc @ Register::A, index @ Register::X)
+        | (acc @ Register::C, index @ Register::X)
+        | (acc @ Register::A, index @ Register::Y)
+        | (acc @ Register::C, index @ Register::Y) => {
+            check_width(context, acc, function_name)?;
+            check_index_width(context, acc, function_name)?;
+            match (to, index) {
+                (Register::X, _) => &[0xAA], // TAX
+                (Register::Y, _) => &[0xA8], // TAY
+                (_, Register::X) => &[0x8A], // TXA
+                _ => &[0x98],                // TYA
+            }
+        }
+        (Register::DB, Register::PB) => &[0x4B, 0xAB], // PHK PLB
+        (Register::DB, reg) => {
+            check_byte(context, reg, function_name)?;
+            match reg {
+                Register::A => &[0x48, 0xAB], // PHA PLB
+                Register::X => &[0xDA, 0xAB], // PHX PLB
+                Register::Y => &[0x5A, 0xAB], // PHY PLB
+                _ => return Ok(None),
+            }
+        }
+        (reg, bank @ Register::DB) | (reg, bank @ Register::PB) => {
+            check_byte(context, reg, function_name)?;
+            match (reg, bank) {
+                (Register::A, Register::DB) => &[0x8B, 0x68], // PHB PLA
+                (Register::X, Register::DB) => &[0x8B, 0xFA], // PHB PLX
+                (Register::Y, Register::DB) => &[0x8B, 0x7A], // PHB PLY
+                (Register::A, _) => &[0x4B, 0x68],            // PHK PLA
+                (Register::X, _) => &[0x4B, 0xFA],            // PHK PLX
+                (Register::Y, _) => &[0x4B, 0x7A],            // PHK PLY
+                _ => return Ok(None),
+            }
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(code))
+}
+
+// The accumulator has to be as wide as the index registers to move
+// between them
+fn check_index_width<'c, 'p: 'c>(
+    context: &'c Context<'p>,
+    register: &Register,
+    function_name: &'p str,
+) -> Result<'p> {
+    match register {
+        Register::A if context.wide_index => Err(Error::InvalidRegister(
+            Register::A,
+            Attribute::WideIndex,
+            function_name,
+        )),
+        Register::C if !context.wide_index => Err(Error::InvalidRegister(
+            Register::C,
+            Attribute::NarrowIndex,
+            function_name,
+        )),
+        _ => Ok(()),
+    }
+}
+
+// The bank registers are a byte, so anything pushed or pulled for them
+// has to be too
+fn check_byte<'c, 'p: 'c>(
+    context: &'c Context<'p>,
+    register: &Register,
+    function_name: &'p str,
+) -> Result<'p> {
+    match register {
+        Register::A => check_width(context, register, function_name),
+        Register::X | Register::Y if context.wide_index => Err(Error::InvalidRegister(
+            register.clone(),
+            Attribute::WideIndex,
+            function_name,
+        )),
+        _ => Ok(()),
+    }
+}
+
+// Assemble a block, switching into its modes on the way in and back
+// out again at the end
+fn assemble_block<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    block: &'p Block<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    let mut emulation = context.emulation;
+    let mut wide_math = context.wide_math;
+    let mut wide_index = context.wide_index;
+    update_codegen(context, &block.attributes, function_name)?;
+    update_emulation(context, emulation, wide_math, wide_index, function_name)?;
+    update_mx(context, wide_math, wide_index, function_name)?;
+    for statement in &block.instructions {
+        assemble_statement(context, statement, function_name);
+    }
+    std::mem::swap(&mut emulation, &mut context.emulation);
+    std::mem::swap(&mut wide_math, &mut context.wide_math);
+    std::mem::swap(&mut wide_index, &mut context.wide_index);
+    update_emulation(context, emulation, wide_math, wide_index, function_name)?;
+    update_mx(context, wide_math, wide_index, function_name)?;
+    Ok(())
+}
+
+// Where a loop decides whether to go round again. WHILE loops jump
+// down to their condition on the way in.
+enum LoopTest<'p> {
+    Forever,
+    DoWhile(&'p Conditional<'p>),
+    While(&'p Conditional<'p>),
+    For(&'p Register, u32, u32),
+}
+
+// Assemble any kind of loop
+fn assemble_loop<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    block: &'p Block<'p>,
+    test: LoopTest<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    let mut emulation = context.emulation;
+    let mut wide_math = context.wide_math;
+    let mut wide_index = context.wide_index;
+    update_codegen(context, &block.attributes, function_name)?;
+    update_emulation(context, emulation, wide_math, wide_index, function_name)?;
+    update_mx(context, wide_math, wide_index, function_name)?;
+    let entry = match test {
+        LoopTest::While(_) => {
+            context
+                .bank()
+                .push_code("While", function_name, &[0x80, 0x00])?; // BRA
+            Some(context.bank().code.len() - 2)
+        }
+        LoopTest::For(reg, from, to) => {
+            // DEX/DEY only set a flag on reaching 0, so there's no
+            // cheap way to run the body for 0 too
+            if from > to && to == 0 {
+                return Err(Error::CountDownToZero(reg.clone(), function_name));
+            }
+            let opcode = if *reg == Register::X { 0xA2 } else { 0xA0 }; // LDX/LDY imm
+            let instruction = index_immediate(context, opcode, from, function_name)?;
+            context
+                .bank()
+                .push_code("For", function_name, &instruction)?;
+            None
+        }
+        _ => None,
+    };
+    let loop_start = context.bank().code.len();
+    let mut body_emulation = context.emulation;
+    let mut body_wide_math = context.wide_math;
+    let mut body_wide_index = context.wide_index;
+    // The loop stays on the stack until its jumps are all resolved,
+    // since widening the entry branch moves its start
+    context.loops.push(Loop {
+        start: loop_start,
+        conditional: !matches!(test, LoopTest::Forever),
+        emulation: context.emulation,
+        wide_math: context.wide_math,
+        wide_index: context.wide_index,
+        exit_emulation: emulation,
+        exit_wide_math: wide_math,
+        exit_wide_index: wide_index,
+    });
+    for statement in &block.instructions {
+        assemble_statement(context, statement, function_name);
+    }
+    // BYTES can leave the body in other modes, which have to be put
+    // back before going round again
+    std::mem::swap(&mut body_emulation, &mut context.emulation);
+    std::mem::swap(&mut body_wide_math, &mut context.wide_math);
+    std::mem::swap(&mut body_wide_index, &mut context.wide_index);
+    update_emulation(
+        context,
+        body_emulation,
+        body_wide_math,
+        body_wide_index,
+        function_name,
+    )?;
+    update_mx(context, body_wide_math, body_wide_index, function_name)?;
+    let continue_target = context.bank().code.len();
+    resolve_loop_relocations(context, continue_target, false, function_name)?;
+    if let Some(entry) = entry {
+        jump(context, entry, continue_target, function_name)?;
+    }
+    let loop_start = context.loops.last().map_or(loop_start, |l| l.start);
+    match test {
+        LoopTest::Forever => {
+            context
+                .bank()
+                .push_code("Loop", function_name, &[0x80, 0x00])?; // BRA
+            let site = context.bank().code.len() - 2;
+            jump(context, site, loop_start, function_name)?;
+        }
+        LoopTest::DoWhile(cond) | LoopTest::While(cond) => {
+            assemble_conditional(context, cond, Some(loop_start), false, function_name)?;
+        }
+        LoopTest::For(reg, from, to) => {
+            // Count towards the end, and stop once the counter steps
+            // past it. When that lands on zero the INX/DEX has already
+            // set the flag, so there's no need to compare. Counting
+            // down to 0 was turned away above.
+            let max = if context.wide_index { 0xFFFF } else { 0xFF };
+            let (step, past) = match (reg, from <= to) {
+                (Register::X, true) => (0xE8, to.wrapping_add(1)), // INX
+                (Register::X, false) => (0xCA, to.wrapping_sub(1)), // DEX
+                (_, true) => (0xC8, to.wrapping_add(1)),           // INY
+                (_, false) => (0x88, to.wrapping_sub(1)),          // DEY
+            };
+            if to > max {
+                return Err(Error::InvalidValue(to, function_name));
+            }
+            context.bank().push_code("For", function_name, &[step])?;
+            if past & max != 0 {
+                let opcode = if *reg == Register::X { 0xE0 } else { 0xC0 }; // CPX/CPY imm
+                let instruction = index_immediate(context, opcode, past & max, function_name)?;
+                context
+                    .bank()
+                    .push_code("For", function_name, &instruction)?;
+            }
+            assemble_branch(context, 0xD0, Some(loop_start), function_name)?; // BNE
+        }
+    }
+    std::mem::swap(&mut emulation, &mut context.emulation);
+    std::mem::swap(&mut wide_math, &mut context.wide_math);
+    std::mem::swap(&mut wide_index, &mut context.wide_index);
+    update_emulation(context, emulation, wide_math, wide_index, function_name)?;
+    update_mx(context, wide_math, wide_index, function_name)?;
+    let break_target = context.bank().code.len();
+    resolve_loop_relocations(context, break_target, true, function_name)?;
+    context.loops.pop();
+    Ok(())
+}
+
+// An X or Y instruction with an immediate sized to the index registers
+fn index_immediate<'p>(
+    context: &Context<'p>,
+    opcode: u8,
+    value: u32,
+    function_name: &'p str,
+) -> std::result::Result<Vec<u8>, Error<'p>> {
+    let len = if context.wide_index { 2 } else { 1 };
+    if value >> (len * 8) != 0 {
+        return Err(Error::InvalidValue(value, function_name));
+    }
+    let mut instruction = vec![opcode];
+    instruction.extend_from_slice(&value.to_le_bytes()[0..len]);
+    Ok(instruction)
+}
+
+// Assemble an IF block and its ELSE, if any. The condition is inverted
+// to branch past the block when it doesn't hold.
+fn assemble_if<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    block: &'p Block<'p>,
+    cond: &'p Conditional<'p>,
+    else_block: &'p Option<Block<'p>>,
+    function_name: &'p str,
+) -> Result<'p> {
+    assemble_conditional(context, cond, None, true, function_name)?;
+    let skip = context.bank().code.len() - 2;
+    assemble_block(context, block, function_name)?;
+    if let Some(else_block) = else_block {
+        assemble_else(context, else_block, skip, function_name)
+    } else {
+        let end = context.bank().code.len();
+        jump(context, skip, end, function_name)
+    }
+}
+
+// The end of the IF block jumps over the ELSE block. Both blocks
+// switch back to the surrounding modes at their end, so they always
+// meet up in the same state.
+fn assemble_else<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    block: &'p Block<'p>,
+    skip: usize,
+    function_name: &'p str,
+) -> Result<'p> {
+    context
+        .bank()
+        .push_code("Skip Else", function_name, &[0x80, 0x00])?; // BRA
+    let else_start = context.bank().code.len();
+    assemble_block(context, block, function_name)?;
+    let end = context.bank().code.len();
+    // Widening either branch moves the other one's target, so they're
+    // resolved together
+    context.jumps.push(Jump {
+        site: else_start - 2,
+        target: end,
+    });
+    context.jumps.push(Jump {
+        site: skip,
+        target: else_start,
+    });
+    resolve_jumps(context, function_name)
+}
+
+// Point the jump or branch at `site` at `target`, and keep it pointed
+// there when code moves
+fn jump<'p>(
+    context: &mut Context<'p>,
+    site: usize,
+    target: usize,
+    function_name: &'p str,
+) -> Result<'p> {
+    context.jumps.push(Jump { site, target });
+    resolve_jumps(context, function_name)
+}
+
+// Write the target of every jump into the code. A branch that can't
+// reach its target is widened, and since that moves code around, the
+// rest are checked all over again.
+fn resolve_jumps<'p>(context: &mut Context<'p>, function_name: &'p str) -> Result<'p> {
+    let mut index = 0;
+    while index < context.jumps.len() {
+        let Jump { site, target } = context.jumps[index];
+        let bank = &mut context.banks[context.current_bank];
+        if bank.code[site] == 0x4C {
+            // JMP abs
+            let bytes = (bank.start + target).to_le_bytes();
+            bank.code[site + 1] = bytes[0];
+            bank.code[site + 2] = bytes[1];
+        } else {
+            let offset = target as isize - (site as isize + 2);
+            if !(-128..=127).contains(&offset) {
+                widen(context, index, function_name)?;
+                index = 0;
+                continue;
+            }
+            bank.code[site + 1] = offset as u8;
+        }
+        index += 1;
+    }
+    Ok(())
+}
+
+// Replace a branch that can't reach with a JMP, or with a branch the
+// other way around a JMP, and move everything after it along
+fn widen<'p>(context: &mut Context<'p>, index: usize, function_name: &'p str) -> Result<'p> {
+    let Jump { site, target } = context.jumps[index];
+    let current_bank = context.current_bank;
+    let bank = &mut context.banks[current_bank];
+    let opcode = bank.code[site];
+    let (code, jmp): (&[u8], usize) = if opcode == 0x80 {
+        (&[0x4C, 0x00, 0x00], 0) // JMP abs
+    } else {
+        (&[opcode ^ 0x20, 3, 0x4C, 0x00, 0x00], 2)
+    };
+    let grow = code.len() - 2;
+    if bank.code.len() + grow > bank.len {
+        return Err(Error::NoSpace("Branch", function_name));
+    }
+    bank.code.splice(site..site + 2, code.iter().copied());
+
+    let moved = |offset: usize| {
+        if offset >= site + 2 {
+            offset + grow
+        } else {
+            offset
+        }
+    };
+    for chunk in &mut bank.chunks {
+        if chunk.offset >= site + 2 {
+            chunk.offset += grow;
+            chunk.addr += grow;
+        } else if chunk.offset + chunk.len > site {
+            chunk.len += grow;
+        }
+    }
+    for relocation in &mut context.relocations {
+        match relocation {
+            Relocation::Function(_, bank, fixup) | Relocation::LongFunction(_, bank, fixup)
+                if *bank == current_bank =>
+            {
+                *fixup = moved(*fixup)
+            }
+            Relocation::Break(fixup) | Relocation::Continue(fixup) => *fixup = moved(*fixup),
+            _ => {}
+        }
+    }
+    for target in &mut context.loops {
+        target.start = moved(target.start);
+    }
+    for jump in &mut context.jumps {
+        jump.site = moved(jump.site);
+        jump.target = moved(jump.target);
+    }
+    context.jumps[index] = Jump {
+        site: site + jmp,
+        target: moved(target),
+    };
+    Ok(())
+}
+
 fn assemble_conditional<'a, 'c, 'p: 'c>(
     context: &'c mut Context<'p>,
     conditional: &'p Conditional<'p>,
@@ -671,112 +1969,396 @@ fn assemble_conditional<'a, 'c, 'p: 'c>(
     invert: bool,
     function_name: &'p str,
 ) -> Result<'p> {
-    let zero = invert
-        ^ match conditional {
-            Conditional::NotBitTest(l, r) => match (l, r) {
-                (Operand::Register(Register::A), Operand::Immediate(value)) => {
-                    if context.wide_math {
-                        return Err(Error::InvalidRegister(
-                            Register::A,
-                            Attribute::WideMath,
-                            function_name,
-                        ));
-                    }
-
-                    if *value > 0xFF {
-                        return Err(Error::InvalidValue(*value, function_name));
-                    }
-                    let bytes = value.to_le_bytes();
-                    let instruction = [0x89, bytes[0]]; // BIT imm
-                    context
-                        .bank
-                        .push_code("BIT A imm", function_name, &instruction)?;
+    // The branch to take when the condition holds
+    let opcode = match conditional {
+        Conditional::NotBitTest(l, r) => match (l, r) {
+            (Operand::Register(Register::A), Operand::Immediate(value)) => {
+                if context.wide_math {
+                    return Err(Error::InvalidRegister(
+                        Register::A,
+                        Attribute::WideMath,
+                        function_name,
+                    ));
+                }
 
-                    true
+                if *value > 0xFF {
+                    return Err(Error::InvalidValue(*value, function_name));
                 }
-                _ => return Err(Error::BadBitTest(l, r, function_name)),
-            },
-            Conditional::BitTest(l, r) => match (l, r) {
-                (Operand::Register(Register::A), Operand::Immediate(value)) => {
-                    if context.wide_math {
-                        return Err(Error::InvalidRegister(
-                            Register::A,
-                            Attribute::WideMath,
-                            function_name,
-                        ));
-                    }
+                let bytes = value.to_le_bytes();
+                let instruction = [0x89, bytes[0]]; // BIT imm
+                context
+                    .bank()
+                    .push_code("BIT A imm", function_name, &instruction)?;
 
-                    if *value > 0xFF {
-                        return Err(Error::InvalidValue(*value, function_name));
-                    }
-                    let bytes = value.to_le_bytes();
-                    let instruction = [0x89, bytes[0]]; // BIT imm
-                    context
-                        .bank
-                        .push_code("BIT A imm", function_name, &instruction)?;
+                0xF0 // BEQ
+            }
+            _ => return Err(Error::BadBitTest(l, r, function_name)),
+        },
+        Conditional::BitTest(l, r) => match (l, r) {
+            (Operand::Register(Register::A), Operand::Immediate(value)) => {
+                if context.wide_math {
+                    return Err(Error::InvalidRegister(
+                        Register::A,
+                        Attribute::WideMath,
+                        function_name,
+                    ));
+                }
 
-                    false
+                if *value > 0xFF {
+                    return Err(Error::InvalidValue(*value, function_name));
                 }
-                (Operand::Register(Register::C), Operand::Immediate(value)) => {
-                    if !context.wide_math {
-                        return Err(Error::InvalidRegister(
-                            Register::C,
-                            Attribute::WideMath,
-                            function_name,
-                        ));
-                    }
+                let bytes = value.to_le_bytes();
+                let instruction = [0x89, bytes[0]]; // BIT imm
+                context
+                    .bank()
+                    .push_code("BIT A imm", function_name, &instruction)?;
 
-                    if *value > 0xFFFF {
-                        return Err(Error::InvalidValue(*value, function_name));
-                    }
-                    let bytes = value.to_le_bytes();
-                    let instruction = [0x89, bytes[0], bytes[1]]; // BIT imm
-                    context
-                        .bank
-                        .push_code("BIT A imm", function_name, &instruction)?;
+                0xD0 // BNE
+            }
+            (Operand::Register(Register::C), Operand::Immediate(value)) => {
+                if !context.wide_math {
+                    return Err(Error::InvalidRegister(
+                        Register::C,
+                        Attribute::NarrowMath,
+                        function_name,
+                    ));
+                }
 
-                    false
+                if *value > 0xFFFF {
+                    return Err(Error::InvalidValue(*value, function_name));
                 }
-                _ => return Err(Error::BadBitTest(l, r, function_name)),
-            },
-            Conditional::Equality(l, r) => match (l, r) {
-                (Operand::Register(Register::A), Operand::Immediate(value)) => {
-                    if context.wide_math {
-                        return Err(Error::InvalidRegister(
-                            Register::A,
-                            Attribute::WideMath,
-                            function_name,
-                        ));
-                    }
+                let bytes = value.to_le_bytes();
+                let instruction = [0x89, bytes[0], bytes[1]]; // BIT imm
+                context
+                    .bank()
+                    .push_code("BIT A imm", function_name, &instruction)?;
 
-                    if *value > 0xFF {
-                        return Err(Error::InvalidValue(*value, function_name));
-                    }
-                    let bytes = value.to_le_bytes();
-                    let instruction = [0xC9, bytes[0]]; // CMP imm
-                    context
-                        .bank
-                        .push_code("CMP A imm", function_name, &instruction)?;
+                0xD0 // BNE
+            }
+            _ => return Err(Error::BadBitTest(l, r, function_name)),
+        },
+        Conditional::Flag(flag) => flag_branch(*flag),
+        Conditional::NotFlag(flag) => flag_branch(*flag) ^ 0x20,
+        Conditional::Equality(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadEquality(l, r, function_name),
+                function_name,
+            )?;
+            0xF0 // BEQ
+        }
+        Conditional::Inequality(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadEquality(l, r, function_name),
+                function_name,
+            )?;
+            0xD0 // BNE
+        }
+        Conditional::Less(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadComparison(l, r, function_name),
+                function_name,
+            )?;
+            0x90 // BCC
+        }
+        Conditional::GreaterEqual(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadComparison(l, r, function_name),
+                function_name,
+            )?;
+            0xB0 // BCS
+        }
+        Conditional::Greater(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadComparison(l, r, function_name),
+                function_name,
+            )?;
+            clear_carry_if_equal(context, function_name)?;
+            0xB0 // BCS
+        }
+        Conditional::LessEqual(l, r) => {
+            assemble_compare(
+                context,
+                l,
+                r,
+                Error::BadComparison(l, r, function_name),
+                function_name,
+            )?;
+            clear_carry_if_equal(context, function_name)?;
+            0x90 // BCC
+        }
+        Conditional::SignedLess(l, r) => {
+            assemble_signed_compare(context, l, r, false, function_name)?;
+            0xB0 // BCS
+        }
+        Conditional::SignedGreaterEqual(l, r) => {
+            assemble_signed_compare(context, l, r, false, function_name)?;
+            0x90 // BCC
+        }
+        Conditional::SignedGreater(l, r) => {
+            assemble_signed_compare(context, l, r, true, function_name)?;
+            0xB0 // BCS
+        }
+        Conditional::SignedLessEqual(l, r) => {
+            assemble_signed_compare(context, l, r, true, function_name)?;
+            0x90 // BCC
+        }
+    };
+    let opcode = if invert { opcode ^ 0x20 } else { opcode };
+    assemble_branch(context, opcode, target, function_name)
+}
 
-                    true
-                }
-                _ => return Err(Error::BadEquality(l, r, function_name)),
-            },
+// Branch back to a loop's start, or forward to a spot patched in later
+fn assemble_branch<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    opcode: u8,
+    target: Option<usize>,
+    function_name: &'p str,
+) -> Result<'p> {
+    context
+        .bank()
+        .push_code("Loop CC", function_name, &[opcode, 0x00])?;
+    match target {
+        Some(target) => {
+            let site = context.bank().code.len() - 2;
+            jump(context, site, target, function_name)
+        }
+        None => Ok(()),
+    }
+}
+
+// The branch taken when a flag is set
+fn flag_branch(flag: Flag) -> u8 {
+    match flag {
+        Flag::Carry => 0xB0,    // BCS
+        Flag::Negative => 0x30, // BMI
+        Flag::Overflow => 0x70, // BVS
+        Flag::Zero => 0xF0,     // BEQ
+    }
+}
+
+// CMP, CPX or CPY a register against an immediate or memory
+fn assemble_compare<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    lhs: &'p Operand<'p>,
+    rhs: &'p Operand<'p>,
+    error: Error<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    let base = match lhs {
+        Operand::Register(Register::A) | Operand::Register(Register::C) => {
+            return assemble_accumulator_op(context, "CMP", 0xC0, lhs, rhs, error, function_name);
+        }
+        Operand::Register(Register::X) => 0xE0,
+        Operand::Register(Register::Y) => 0xC0,
+        _ => return Err(error),
+    };
+    if let Operand::Immediate(value) = rhs {
+        let max = if context.wide_index { 0xFFFF } else { 0xFF };
+        if *value > max {
+            return Err(Error::InvalidValue(*value, function_name));
+        }
+        let len = if context.wide_index { 3 } else { 2 };
+        let bytes = value.to_le_bytes();
+        let instruction = [base, bytes[0], bytes[1]];
+        context
+            .bank()
+            .push_code("CPX/CPY imm", function_name, &instruction[0..len])
+    } else {
+        let (mode, addr) = match memory_operand(context, rhs, function_name)? {
+            Some(operand) => operand,
+            None => return Err(error),
         };
-    let loop_length = target
-        .map(|target| context.bank.code.len() - target)
-        .unwrap_or(0);
-    let offset = (!(loop_length as u8 + 2)) + 1;
-    if loop_length > 126 {
-        return Err(Error::LoopTooLong(function_name));
+        let opcode = match mode {
+            Mode::Direct => base | 0x04,
+            Mode::Absolute => base | 0x0C,
+            _ => return Err(far_operand_or(mode, rhs, error, function_name)),
+        };
+        push_memory(context, "CPX/CPY", function_name, opcode, mode, addr)
+    }
+}
+
+// After a compare, carry is set for greater-or-equal. Clearing it
+// when the zero flag says the two were equal leaves it set only for
+// strictly greater.
+fn clear_carry_if_equal<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    function_name: &'p str,
+) -> Result<'p> {
+    context
+        .bank()
+        .push_code("Compare >", function_name, &[0xD0, 0x01, 0x18]) // BNE +1; CLC
+}
+
+// CMP doesn't set the overflow flag, so signed comparisons subtract
+// instead and correct the sign of the result when it overflowed. The
+// sign is shifted into carry so A can be restored with a PLA. This
+// leaves carry set if lhs < rhs, or if rhs < lhs when swapped.
+fn assemble_signed_compare<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    lhs: &'p Operand<'p>,
+    rhs: &'p Operand<'p>,
+    swapped: bool,
+    function_name: &'p str,
+) -> Result<'p> {
+    let error = || Error::BadComparison(lhs, rhs, function_name);
+    match lhs {
+        Operand::Register(reg @ Register::A) | Operand::Register(reg @ Register::C) => {
+            check_width(context, reg, function_name)?
+        }
+        // Only SBC sets the overflow flag, and there's no index form
+        Operand::Register(reg @ Register::X) | Operand::Register(reg @ Register::Y) => {
+            return Err(Error::SignedIndexCompare(reg.clone(), function_name))
+        }
+        _ => return Err(error()),
+    }
+    let wide = context.wide_math;
+    let operand = match rhs {
+        Operand::Immediate(value) => {
+            let max = if wide { 0xFFFF } else { 0xFF };
+            if *value > max {
+                return Err(Error::InvalidValue(*value, function_name));
+            }
+            None
+        }
+        _ => {
+            let (mode, mut addr) =
+                memory_operand(context, rhs, function_name)?.ok_or_else(error)?;
+            mode.accumulator_opcode(0).ok_or_else(error)?;
+            // A is on the stack while the operand is read
+            if let Mode::Stack | Mode::StackIndirectY = mode {
+                addr = byte(addr + if wide { 2 } else { 1 }, function_name)?;
+            }
+            Some((mode, addr))
+        }
+    };
+    let operand_instruction = |base: u8| {
+        let (opcode, len, value) = match (operand, rhs) {
+            (Some((mode, addr)), _) => (
+                mode.accumulator_opcode(base).unwrap(),
+                mode.operand_len(),
+                addr,
+            ),
+            (None, Operand::Immediate(value)) => (base | 0x09, if wide { 2 } else { 1 }, *value),
+            _ => unreachable!(),
+        };
+        let mut instruction = vec![opcode];
+        instruction.extend_from_slice(&value.to_le_bytes()[0..len]);
+        instruction
+    };
+
+    let mut instruction = vec![0x48]; // PHA
+    if swapped {
+        instruction.extend(operand_instruction(0xA0)); // LDA rhs
+        instruction.extend_from_slice(&[0x38, 0xE3, 0x01]); // SEC; SBC 1,S
+    } else {
+        instruction.push(0x38); // SEC
+        instruction.extend(operand_instruction(0xE0)); // SBC rhs
     }
-    let opcode = if zero { 0xF0 } else { 0xD0 };
+    if wide {
+        instruction.extend_from_slice(&[0x50, 0x03, 0x49, 0x00, 0x80]); // BVC; EOR #$8000
+    } else {
+        instruction.extend_from_slice(&[0x50, 0x02, 0x49, 0x80]); // BVC; EOR #$80
+    }
+    instruction.extend_from_slice(&[0x0A, 0x68]); // ASL A; PLA
     context
-        .bank
-        .push_code("Loop CC", function_name, &[opcode, offset])?;
+        .bank()
+        .push_code("Signed compare", function_name, &instruction)
+}
+
+fn select_bank<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    attributes: &[Attribute],
+    function_name: &'p str,
+) -> Result<'p> {
+    let bank = attribute_bank(attributes);
+    if !context.map.valid_bank(bank) {
+        return Err(Error::InvalidBank(bank, function_name));
+    }
+    let bank = bank as usize;
+    while context.banks.len() <= bank {
+        let next = context.banks.len();
+        context.banks.push(Bank::new(next, context.map));
+    }
+    context.current_bank = bank;
     Ok(())
 }
 
+fn attribute_bank(attributes: &[Attribute]) -> u32 {
+    attributes
+        .iter()
+        .find_map(|attr| match attr {
+            Attribute::Bank(bank) => Some(*bank),
+            _ => None,
+        })
+        .unwrap_or(0)
+}
+
+// Point any BREAK (or CONTINUE) jumps made since the start of the
+// innermost loop at their target. Jumps from loops nested inside it
+// have already been resolved, and jumps from before the loop belong
+// to an outer one.
+fn resolve_loop_relocations<'p>(
+    context: &mut Context<'p>,
+    target: usize,
+    breaks: bool,
+    function_name: &'p str,
+) -> Result<'p> {
+    let start = context.loops.last().map_or(0, |l| l.start);
+    let jumps = &mut context.jumps;
+    context.relocations.retain(|relo| {
+        let fixup = match (relo, breaks) {
+            (Relocation::Break(fixup), true) | (Relocation::Continue(fixup), false) => *fixup,
+            _ => return true,
+        };
+        if fixup < start {
+            return true;
+        }
+        jumps.push(Jump {
+            site: fixup - 1,
+            target,
+        });
+        false
+    });
+    resolve_jumps(context, function_name)
+}
+
+// Emit whatever mode changes are needed to jump from the current
+// point into code expecting the given mode, without changing the mode
+// codegen carries on with afterwards.
+fn switch_mode<'c, 'p: 'c>(
+    context: &'c mut Context<'p>,
+    emulation: bool,
+    wide_math: bool,
+    wide_index: bool,
+    function_name: &'p str,
+) -> Result<'p> {
+    let current = (context.emulation, context.wide_math, context.wide_index);
+    context.emulation = emulation;
+    context.wide_math = wide_math;
+    context.wide_index = wide_index;
+    let result = update_emulation(context, current.0, current.1, current.2, function_name)
+        .and_then(|_| update_mx(context, current.1, current.2, function_name));
+    context.emulation = current.0;
+    context.wide_math = current.1;
+    context.wide_index = current.2;
+    result
+}
+
 fn update_codegen<'a, 'c, 'p: 'c>(
     context: &'c mut Context<'p>,
     attributes: &'a [Attribute],
@@ -880,20 +2462,33 @@ fn update_codegen<'a, 'c, 'p: 'c>(
     Ok(())
 }
 
+// Switch in or out of emulation mode. This always happens before
+// update_mx, so it's also told the widths that the switch runs in.
 fn update_emulation<'c, 'p: 'c>(
     context: &'c mut Context<'p>,
     emulation: bool,
+    wide_math: bool,
+    wide_index: bool,
     function_name: &'p str,
 ) -> Result<'p> {
     if emulation != context.emulation {
+        let before = State {
+            emulation,
+            wide_math,
+            wide_index,
+        };
         if context.emulation {
-            context
-                .bank
-                .push_code("Enable Emulation", function_name, &[0x38, 0xFB])?;
+            context.bank_from(before).push_code(
+                "Enable Emulation",
+                function_name,
+                &[0x38, 0xFB],
+            )?;
         } else {
-            context
-                .bank
-                .push_code("Disable Emulation", function_name, &[0x18, 0xFB])?;
+            context.bank_from(before).push_code(
+                "Disable Emulation",
+                function_name,
+                &[0x18, 0xFB],
+            )?;
         }
     }
     Ok(())
@@ -905,56 +2500,235 @@ fn update_mx<'c, 'p: 'c>(
     wide_index: bool,
     function_name: &'p str,
 ) -> Result<'p> {
+    let before = State {
+        emulation: context.emulation,
+        wide_math,
+        wide_index,
+    };
     if context.wide_math == context.wide_index {
         // Set both, even if one didn't change. Easier codegen
-        if context.wide_math != wide_math && context.wide_math {
-            context
-                .bank
-                .push_code("Enable Wide Math + Index", function_name, &[0xC2, 0x30])?;
-        } else if context.wide_math != wide_math {
-            context
-                .bank
-                .push_code("Disable wide math + index", function_name, &[0xE2, 0x30])?;
+        let changed = context.wide_math != wide_math || context.wide_index != wide_index;
+        if changed && context.wide_math {
+            context.bank_from(before).push_code(
+                "Enable Wide Math + Index",
+                function_name,
+                &[0xC2, 0x30],
+            )?;
+        } else if changed {
+            context.bank_from(before).push_code(
+                "Disable wide math + index",
+                function_name,
+                &[0xE2, 0x30],
+            )?;
         }
     } else {
         if wide_math != context.wide_math {
             if context.wide_math {
-                context
-                    .bank
-                    .push_code("Enable Wide Math", function_name, &[0xC2, 0x20])?;
+                context.bank_from(before).push_code(
+                    "Enable Wide Math",
+                    function_name,
+                    &[0xC2, 0x20],
+                )?;
             } else {
-                context
-                    .bank
-                    .push_code("Disable Wide Math", function_name, &[0xE2, 0x20])?;
+                context.bank_from(before).push_code(
+                    "Disable Wide Math",
+                    function_name,
+                    &[0xE2, 0x20],
+                )?;
             }
         }
 
+        // By now the accumulator is in its new mode
+        let before = State {
+            wide_math: context.wide_math,
+            ..before
+        };
         if wide_index != context.wide_index {
             if context.wide_index {
-                context
-                    .bank
-                    .push_code("Enable Wide Index", function_name, &[0xC2, 0x10])?;
+                context.bank_from(before).push_code(
+                    "Enable Wide Index",
+                    function_name,
+                    &[0xC2, 0x10],
+                )?;
             } else {
-                context
-                    .bank
-                    .push_code("Disable Wide Index", function_name, &[0xE2, 0x10])?;
+                context.bank_from(before).push_code(
+                    "Disable Wide Index",
+                    function_name,
+                    &[0xE2, 0x10],
+                )?;
             }
         }
     }
     Ok(())
 }
 
-impl Bank {
-    fn push_code<'c, 'p: 'c, 'a>(
-        &'c mut self,
+impl<'a> Context<'a> {
+    fn bank(&mut self) -> &mut Bank<'a> {
+        let bank = &mut self.banks[self.current_bank];
+        bank.state = State {
+            emulation: self.emulation,
+            wide_math: self.wide_math,
+            wide_index: self.wide_index,
+        };
+        bank.span = self.span;
+        bank
+    }
+
+    // The bank, for code that runs in some other modes than the
+    // current ones
+    fn bank_from(&mut self, state: State) -> &mut Bank<'a> {
+        let bank = self.bank();
+        bank.state = state;
+        bank
+    }
+
+    // Mark a point that codegen can later back up to, so that a
+    // statement that failed can be dropped
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            code: self.banks[self.current_bank].code.len(),
+            relocations: self.relocations.len(),
+            jumps: self.jumps.len(),
+            errors: self.errors.len(),
+            emulation: self.emulation,
+            wide_math: self.wide_math,
+            wide_index: self.wide_index,
+        }
+    }
+
+    // Record an error against whatever is currently being assembled
+    fn fail(&mut self, error: Error<'a>) {
+        let span = self.span;
+        self.errors.push(LocatedError { error, span });
+    }
+
+    fn rewind(&mut self, checkpoint: Checkpoint) {
+        self.bank().code.truncate(checkpoint.code);
+        self.bank()
+            .chunks
+            .retain(|chunk| chunk.offset < checkpoint.code);
+        self.relocations.truncate(checkpoint.relocations);
+        self.jumps.truncate(checkpoint.jumps);
+        self.errors.truncate(checkpoint.errors);
+        self.emulation = checkpoint.emulation;
+        self.wide_math = checkpoint.wide_math;
+        self.wide_index = checkpoint.wide_index;
+    }
+}
+
+impl MemoryMap {
+    fn map_mode(&self) -> u8 {
+        match self {
+            MemoryMap::LoRom => 0x20,
+            MemoryMap::HiRom => 0x21,
+            MemoryMap::ExHiRom => 0x25,
+        }
+    }
+
+    fn bank_size(&self) -> usize {
+        match self {
+            MemoryMap::LoRom => 0x8000,
+            MemoryMap::HiRom | MemoryMap::ExHiRom => 0x10000,
+        }
+    }
+
+    fn valid_bank(&self, bank: u32) -> bool {
+        match self {
+            MemoryMap::LoRom | MemoryMap::HiRom => bank <= 0x3F,
+            // 0x3E and 0x3F would land on WRAM at 0x7E and 0x7F
+            MemoryMap::ExHiRom => bank <= 0x3D || (0x40..=0x7F).contains(&bank),
+        }
+    }
+
+    // The ROM offset of the header, which always sits just below the
+    // vectors at the end of bank 0x00
+    fn header_offset(&self) -> usize {
+        match self {
+            MemoryMap::LoRom => 0x7FC0,
+            MemoryMap::HiRom => 0xFFC0,
+            MemoryMap::ExHiRom => 0x40FFC0,
+        }
+    }
+}
+
+impl<'a> Bank<'a> {
+    // Bank 0 is always the upper half of bank 0x00, so that it can
+    // hold interrupt handlers and the header. HiROM banks past that
+    // are a full 64KB at 0xC0 and up. ExHiROM has its second 4MB
+    // first, so that bank 0x00 can be mirrored from it.
+    fn new(bank: usize, map: MemoryMap) -> Self {
+        let (start, offset, len) = match (map, bank) {
+            (MemoryMap::LoRom, 0) => (0x008000, 0x000000, 0x7FC0),
+            (MemoryMap::LoRom, _) => ((bank << 16) | 0x8000, bank * 0x8000, 0x8000),
+            (MemoryMap::HiRom, 0) => (0x008000, 0x008000, 0x7FC0),
+            (MemoryMap::HiRom, _) => ((0xC0 + bank) << 16, bank * 0x10000, 0x10000),
+            (MemoryMap::ExHiRom, 0) => (0x008000, 0x408000, 0x7FC0),
+            (MemoryMap::ExHiRom, 0x01..=0x3F) => {
+                ((0x40 + bank) << 16, 0x400000 + bank * 0x10000, 0x10000)
+            }
+            (MemoryMap::ExHiRom, _) => ((0x80 + bank) << 16, (bank - 0x40) * 0x10000, 0x10000),
+        };
+        Bank {
+            start,
+            offset,
+            len,
+            code: vec![],
+            chunks: vec![],
+            state: State::default(),
+            span: None,
+        }
+    }
+
+    // The full 24-bit address of the next byte of code
+    fn addr(&self) -> usize {
+        self.start + self.code.len()
+    }
+
+    fn push_code(&mut self, operation: &'static str, owner: &'a str, code: &[u8]) -> Result<'a> {
+        self.push(operation, owner, code, false)
+    }
+
+    fn push_data(&mut self, operation: &'static str, owner: &'a str, data: &[u8]) -> Result<'a> {
+        self.push(operation, owner, data, true)
+    }
+
+    fn push(
+        &mut self,
         operation: &'static str,
-        function_name: &'p str,
-        code: &'a [u8],
-    ) -> Result<'p> {
+        owner: &'a str,
+        code: &[u8],
+        data: bool,
+    ) -> Result<'a> {
         if self.code.len() + code.len() > self.len {
-            return Err(Error::NoSpace(operation, function_name));
+            return Err(Error::NoSpace(operation, owner));
         }
+        self.chunks.push(Chunk {
+            addr: self.start + self.code.len(),
+            offset: self.code.len(),
+            len: code.len(),
+            owner,
+            span: self.span,
+            data,
+            state: self.state,
+        });
         self.code.extend_from_slice(code);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn checksum_power_of_two() {
+        let image = vec![1u8; 0x8000];
+        assert_eq!(super::checksum(&image), 0x8000);
+    }
+
+    #[test]
+    fn checksum_mirrors_remainder() {
+        // 3 "banks": the last one is mirrored once to fill out 4
+        let mut image = vec![1u8; 0x30];
+        image[0x20..].iter_mut().for_each(|b| *b = 2);
+        assert_eq!(super::checksum(&image), 0x20 + 0x10 * 2 * 2);
+    }
+}
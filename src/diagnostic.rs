@@ -0,0 +1,132 @@
+use super::{codegen, parser};
+use nom::error::VerboseErrorKind;
+use std::fmt::Write;
+
+/// A readable error message, pointing at the source text it's about
+/// when there is any
+#[derive(Debug, PartialEq)]
+pub struct Diagnostic<'a> {
+    pub message: String,
+    pub span: Option<&'a str>,
+}
+
+impl<'a> Diagnostic<'a> {
+    /// Nom reports the innermost failure first, followed by the
+    /// contexts it was found in
+    pub fn from_parse(error: &parser::Error<'a>) -> Self {
+        let error = match error {
+            nom::Err::Error(e) | nom::Err::Failure(e) => e,
+            nom::Err::Incomplete(_) => {
+                return Diagnostic {
+                    message: "unexpected end of input".to_string(),
+                    span: None,
+                }
+            }
+        };
+        let contexts: Vec<_> = error
+            .errors
+            .iter()
+            .filter_map(|(_, kind)| match kind {
+                VerboseErrorKind::Context(context) => Some(*context),
+                _ => None,
+            })
+            .collect();
+        let expected = match (error.errors.first(), contexts.first()) {
+            (Some((_, VerboseErrorKind::Char(c))), _) => format!("expected `{}`", c),
+            (_, Some(&"open")) => "expected `{`".to_string(),
+            (_, Some(&"close")) => "expected an instruction or `}`".to_string(),
+            (_, Some(&"do_while")) => "a DO loop's WHILE can't have a body".to_string(),
+            _ => "unexpected input".to_string(),
+        };
+        // The pieces of a block aren't worth naming on their own
+        let message = match contexts
+            .iter()
+            .find(|context| !["open", "close", "inner", "body", "do_while"].contains(context))
+        {
+            Some(context) => format!("syntax error: {} in {}", expected, context),
+            None => format!("syntax error: {}", expected),
+        };
+        let span = error.errors.first().map(|(span, _)| *span);
+        Diagnostic { message, span }
+    }
+
+    pub fn from_codegen(error: &codegen::LocatedError<'a>) -> Self {
+        Diagnostic {
+            message: error.error.to_string(),
+            span: error.span.map(|span| span.0),
+        }
+    }
+
+    /// Format the message along with the file, line and column, and
+    /// the line of source underlined
+    pub fn render(&self, path: &str, source: &str) -> String {
+        let mut output = format!("error: {}\n", self.message);
+        let (line, column) = match self.span.and_then(|span| location(source, span)) {
+            Some(location) => location,
+            None => {
+                writeln!(output, " --> {}", path).unwrap();
+                return output;
+            }
+        };
+        let text = source.lines().nth(line - 1).unwrap_or("");
+        let span = self.span.unwrap_or("");
+        let underline = span
+            .lines()
+            .next()
+            .map(|first| first.chars().count())
+            .unwrap_or(0)
+            .max(1);
+        let number = line.to_string();
+        let gutter = " ".repeat(number.len());
+        writeln!(output, "{}--> {}:{}:{}", gutter, path, line, column).unwrap();
+        writeln!(output, "{} |", gutter).unwrap();
+        writeln!(output, "{} | {}", number, text).unwrap();
+        let indent: String = text
+            .chars()
+            .take(column - 1)
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        writeln!(output, "{} | {}{}", gutter, indent, "^".repeat(underline)).unwrap();
+        output
+    }
+}
+
+/// The 1-based line and column a span starts at, if it's part of the
+/// source at all
+pub fn location(source: &str, span: &str) -> Option<(usize, usize)> {
+    let start = source.as_ptr() as usize;
+    let offset = (span.as_ptr() as usize).checked_sub(start)?;
+    if offset + span.len() > source.len() {
+        return None;
+    }
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    Some((line, column))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn location() {
+        let source = "FUN main {\n  A := x;\n}";
+        assert_eq!(super::location(source, &source[13..]), Some((2, 3)));
+        assert_eq!(super::location(source, "elsewhere"), None);
+    }
+
+    #[test]
+    fn render() {
+        let source = "FUN main {\n  A := x;\n}";
+        let diagnostic = Diagnostic {
+            message: "unknown variable `x`".to_string(),
+            span: Some(&source[13..20]),
+        };
+        assert_eq!(
+            diagnostic.render("main.snz", source),
+            "error: unknown variable `x`\n --> main.snz:2:3\n  |\n2 |   A := x;\n  |   ^^^^^^^\n"
+        );
+    }
+}
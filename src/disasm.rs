@@ -0,0 +1,385 @@
+use std::fmt;
+
+/// The processor modes that change how instructions decode
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct State {
+    pub emulation: bool,
+    pub wide_math: bool,
+    pub wide_index: bool,
+}
+
+impl State {
+    /// Follow REP and SEP, which change the width of later immediates
+    pub fn apply(&mut self, instruction: &Instruction) {
+        let bits = match instruction.operand {
+            Operand::Immediate(bits) => bits,
+            _ => return,
+        };
+        match instruction.mnemonic {
+            "REP" => {
+                self.wide_math |= bits & 0x20 != 0;
+                self.wide_index |= bits & 0x10 != 0;
+            }
+            "SEP" => {
+                self.wide_math &= bits & 0x20 == 0;
+                self.wide_index &= bits & 0x10 == 0;
+            }
+            _ => {}
+        }
+        if self.emulation {
+            self.wide_math = false;
+            self.wide_index = false;
+        }
+    }
+}
+
+/// Addressing modes, as far as they change how an instruction decodes
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Implied,
+    Accumulator,
+    ImmediateM,
+    ImmediateX,
+    Immediate8,
+    Immediate16,
+    Direct,
+    DirectX,
+    DirectY,
+    DirectIndirect,
+    DirectIndirectX,
+    DirectIndirectY,
+    DirectIndirectLong,
+    DirectIndirectLongY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    AbsoluteIndirect,
+    AbsoluteIndirectX,
+    AbsoluteIndirectLong,
+    Long,
+    LongX,
+    Relative,
+    RelativeLong,
+    Stack,
+    StackIndirectY,
+    BlockMove,
+}
+
+use Mode::*;
+
+#[rustfmt::skip]
+const OPCODES: [(&str, Mode); 256] = [
+    ("BRK", Immediate8), ("ORA", DirectIndirectX), ("COP", Immediate8), ("ORA", Stack),
+    ("TSB", Direct), ("ORA", Direct), ("ASL", Direct), ("ORA", DirectIndirectLong),
+    ("PHP", Implied), ("ORA", ImmediateM), ("ASL", Accumulator), ("PHD", Implied),
+    ("TSB", Absolute), ("ORA", Absolute), ("ASL", Absolute), ("ORA", Long),
+    ("BPL", Relative), ("ORA", DirectIndirectY), ("ORA", DirectIndirect), ("ORA", StackIndirectY),
+    ("TRB", Direct), ("ORA", DirectX), ("ASL", DirectX), ("ORA", DirectIndirectLongY),
+    ("CLC", Implied), ("ORA", AbsoluteY), ("INC", Accumulator), ("TCS", Implied),
+    ("TRB", Absolute), ("ORA", AbsoluteX), ("ASL", AbsoluteX), ("ORA", LongX),
+    ("JSR", Absolute), ("AND", DirectIndirectX), ("JSL", Long), ("AND", Stack),
+    ("BIT", Direct), ("AND", Direct), ("ROL", Direct), ("AND", DirectIndirectLong),
+    ("PLP", Implied), ("AND", ImmediateM), ("ROL", Accumulator), ("PLD", Implied),
+    ("BIT", Absolute), ("AND", Absolute), ("ROL", Absolute), ("AND", Long),
+    ("BMI", Relative), ("AND", DirectIndirectY), ("AND", DirectIndirect), ("AND", StackIndirectY),
+    ("BIT", DirectX), ("AND", DirectX), ("ROL", DirectX), ("AND", DirectIndirectLongY),
+    ("SEC", Implied), ("AND", AbsoluteY), ("DEC", Accumulator), ("TSC", Implied),
+    ("BIT", AbsoluteX), ("AND", AbsoluteX), ("ROL", AbsoluteX), ("AND", LongX),
+    ("RTI", Implied), ("EOR", DirectIndirectX), ("WDM", Immediate8), ("EOR", Stack),
+    ("MVP", BlockMove), ("EOR", Direct), ("LSR", Direct), ("EOR", DirectIndirectLong),
+    ("PHA", Implied), ("EOR", ImmediateM), ("LSR", Accumulator), ("PHK", Implied),
+    ("JMP", Absolute), ("EOR", Absolute), ("LSR", Absolute), ("EOR", Long),
+    ("BVC", Relative), ("EOR", DirectIndirectY), ("EOR", DirectIndirect), ("EOR", StackIndirectY),
+    ("MVN", BlockMove), ("EOR", DirectX), ("LSR", DirectX), ("EOR", DirectIndirectLongY),
+    ("CLI", Implied), ("EOR", AbsoluteY), ("PHY", Implied), ("TCD", Implied),
+    ("JML", Long), ("EOR", AbsoluteX), ("LSR", AbsoluteX), ("EOR", LongX),
+    ("RTS", Implied), ("ADC", DirectIndirectX), ("PER", RelativeLong), ("ADC", Stack),
+    ("STZ", Direct), ("ADC", Direct), ("ROR", Direct), ("ADC", DirectIndirectLong),
+    ("PLA", Implied), ("ADC", ImmediateM), ("ROR", Accumulator), ("RTL", Implied),
+    ("JMP", AbsoluteIndirect), ("ADC", Absolute), ("ROR", Absolute), ("ADC", Long),
+    ("BVS", Relative), ("ADC", DirectIndirectY), ("ADC", DirectIndirect), ("ADC", StackIndirectY),
+    ("STZ", DirectX), ("ADC", DirectX), ("ROR", DirectX), ("ADC", DirectIndirectLongY),
+    ("SEI", Implied), ("ADC", AbsoluteY), ("PLY", Implied), ("TDC", Implied),
+    ("JMP", AbsoluteIndirectX), ("ADC", AbsoluteX), ("ROR", AbsoluteX), ("ADC", LongX),
+    ("BRA", Relative), ("STA", DirectIndirectX), ("BRL", RelativeLong), ("STA", Stack),
+    ("STY", Direct), ("STA", Direct), ("STX", Direct), ("STA", DirectIndirectLong),
+    ("DEY", Implied), ("BIT", ImmediateM), ("TXA", Implied), ("PHB", Implied),
+    ("STY", Absolute), ("STA", Absolute), ("STX", Absolute), ("STA", Long),
+    ("BCC", Relative), ("STA", DirectIndirectY), ("STA", DirectIndirect), ("STA", StackIndirectY),
+    ("STY", DirectX), ("STA", DirectX), ("STX", DirectY), ("STA", DirectIndirectLongY),
+    ("TYA", Implied), ("STA", AbsoluteY), ("TXS", Implied), ("TXY", Implied),
+    ("STZ", Absolute), ("STA", AbsoluteX), ("STZ", AbsoluteX), ("STA", LongX),
+    ("LDY", ImmediateX), ("LDA", DirectIndirectX), ("LDX", ImmediateX), ("LDA", Stack),
+    ("LDY", Direct), ("LDA", Direct), ("LDX", Direct), ("LDA", DirectIndirectLong),
+    ("TAY", Implied), ("LDA", ImmediateM), ("TAX", Implied), ("PLB", Implied),
+    ("LDY", Absolute), ("LDA", Absolute), ("LDX", Absolute), ("LDA", Long),
+    ("BCS", Relative), ("LDA", DirectIndirectY), ("LDA", DirectIndirect), ("LDA", StackIndirectY),
+    ("LDY", DirectX), ("LDA", DirectX), ("LDX", DirectY), ("LDA", DirectIndirectLongY),
+    ("CLV", Implied), ("LDA", AbsoluteY), ("TSX", Implied), ("TYX", Implied),
+    ("LDY", AbsoluteX), ("LDA", AbsoluteX), ("LDX", AbsoluteY), ("LDA", LongX),
+    ("CPY", ImmediateX), ("CMP", DirectIndirectX), ("REP", Immediate8), ("CMP", Stack),
+    ("CPY", Direct), ("CMP", Direct), ("DEC", Direct), ("CMP", DirectIndirectLong),
+    ("INY", Implied), ("CMP", ImmediateM), ("DEX", Implied), ("WAI", Implied),
+    ("CPY", Absolute), ("CMP", Absolute), ("DEC", Absolute), ("CMP", Long),
+    ("BNE", Relative), ("CMP", DirectIndirectY), ("CMP", DirectIndirect), ("CMP", StackIndirectY),
+    ("PEI", DirectIndirect), ("CMP", DirectX), ("DEC", DirectX), ("CMP", DirectIndirectLongY),
+    ("CLD", Implied), ("CMP", AbsoluteY), ("PHX", Implied), ("STP", Implied),
+    ("JML", AbsoluteIndirectLong), ("CMP", AbsoluteX), ("DEC", AbsoluteX), ("CMP", LongX),
+    ("CPX", ImmediateX), ("SBC", DirectIndirectX), ("SEP", Immediate8), ("SBC", Stack),
+    ("CPX", Direct), ("SBC", Direct), ("INC", Direct), ("SBC", DirectIndirectLong),
+    ("INX", Implied), ("SBC", ImmediateM), ("NOP", Implied), ("XBA", Implied),
+    ("CPX", Absolute), ("SBC", Absolute), ("INC", Absolute), ("SBC", Long),
+    ("BEQ", Relative), ("SBC", DirectIndirectY), ("SBC", DirectIndirect), ("SBC", StackIndirectY),
+    ("PEA", Immediate16), ("SBC", DirectX), ("INC", DirectX), ("SBC", DirectIndirectLongY),
+    ("SED", Implied), ("SBC", AbsoluteY), ("PLX", Implied), ("XCE", Implied),
+    ("JSR", AbsoluteIndirectX), ("SBC", AbsoluteX), ("INC", AbsoluteX), ("SBC", LongX),
+];
+
+/// An operand, already resolved to the value or address it refers to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Operand {
+    None,
+    Accumulator,
+    Immediate(u32),
+    Address(Mode, u32),
+    Target(u32),
+    BlockMove(u8, u8),
+}
+
+/// One decoded instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Instruction {
+    pub mnemonic: &'static str,
+    pub operand: Operand,
+    pub len: usize,
+    width: usize,
+}
+
+/// Decode the instruction at the start of `bytes`, which sits at the
+/// 24-bit address `addr`. Returns `None` if the bytes run out first.
+pub fn decode(bytes: &[u8], addr: u32, state: State) -> Option<Instruction> {
+    let (mnemonic, mode) = OPCODES[*bytes.first()? as usize];
+    let wide_math = state.wide_math && !state.emulation;
+    let wide_index = state.wide_index && !state.emulation;
+    let width = match mode {
+        Implied | Accumulator => 0,
+        ImmediateM => 1 + wide_math as usize,
+        ImmediateX => 1 + wide_index as usize,
+        Immediate8 | Direct | DirectX | DirectY | DirectIndirect | DirectIndirectX
+        | DirectIndirectY | DirectIndirectLong | DirectIndirectLongY | Relative | Stack
+        | StackIndirectY => 1,
+        Immediate16 | Absolute | AbsoluteX | AbsoluteY | AbsoluteIndirect | AbsoluteIndirectX
+        | AbsoluteIndirectLong | RelativeLong | BlockMove => 2,
+        Long | LongX => 3,
+    };
+    let raw = bytes.get(1..1 + width)?;
+    let value = raw
+        .iter()
+        .rev()
+        .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+    // Branches are relative to the next instruction, within the bank
+    let next = addr.wrapping_add(1 + width as u32);
+    let target = |offset: u32| (addr & 0xFF_0000) | (next.wrapping_add(offset) & 0xFFFF);
+    let operand = match mode {
+        Implied => Operand::None,
+        Accumulator => Operand::Accumulator,
+        ImmediateM | ImmediateX | Immediate8 | Immediate16 => Operand::Immediate(value),
+        Relative => Operand::Target(target(value as i8 as u32)),
+        RelativeLong => Operand::Target(target(value)),
+        BlockMove => Operand::BlockMove(raw[0], raw[1]),
+        _ => Operand::Address(mode, value),
+    };
+    Some(Instruction {
+        mnemonic,
+        operand,
+        len: 1 + width,
+        width,
+    })
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let digits = self.width * 2;
+        match self.operand {
+            Operand::None => write!(f, "{}", self.mnemonic),
+            Operand::Accumulator => write!(f, "{} A", self.mnemonic),
+            Operand::Immediate(value) => write!(f, "{} #${:02$X}", self.mnemonic, value, digits),
+            Operand::Target(addr) => write!(f, "{} ${:04X}", self.mnemonic, addr & 0xFFFF),
+            Operand::BlockMove(dest, src) => {
+                write!(f, "{} ${:02X},${:02X}", self.mnemonic, src, dest)
+            }
+            Operand::Address(mode, addr) => {
+                let addr = format!("${:01$X}", addr, digits);
+                let operand = match mode {
+                    DirectX | AbsoluteX | LongX => format!("{},X", addr),
+                    DirectY | AbsoluteY => format!("{},Y", addr),
+                    DirectIndirect | AbsoluteIndirect => format!("({})", addr),
+                    DirectIndirectX | AbsoluteIndirectX => format!("({},X)", addr),
+                    DirectIndirectY => format!("({}),Y", addr),
+                    DirectIndirectLong | AbsoluteIndirectLong => format!("[{}]", addr),
+                    DirectIndirectLongY => format!("[{}],Y", addr),
+                    Stack => format!("{},S", addr),
+                    StackIndirectY => format!("({},S),Y", addr),
+                    _ => addr,
+                };
+                write!(f, "{} {}", self.mnemonic, operand)
+            }
+        }
+    }
+}
+
+/// A line of disassembly: an instruction, or bytes that don't make one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Line<'b> {
+    pub addr: u32,
+    pub bytes: &'b [u8],
+    pub instruction: Option<Instruction>,
+    /// The modes the line was decoded in
+    pub state: State,
+}
+
+/// Decode a run of instructions starting at `addr`, following REP, SEP
+/// and XCE as they change how later immediates decode. Anything left
+/// at the end that isn't a whole instruction comes back as bytes.
+pub fn disassemble(bytes: &[u8], addr: u32, mut state: State) -> Vec<Line<'_>> {
+    let mut lines = vec![];
+    let mut offset = 0;
+    // XCE swaps with the carry, which is only known straight after
+    // a CLC or SEC
+    let mut carry = None;
+    while offset < bytes.len() {
+        let line_addr = addr + offset as u32;
+        let instruction = match decode(&bytes[offset..], line_addr, state) {
+            Some(instruction) => instruction,
+            None => {
+                lines.push(Line {
+                    addr: line_addr,
+                    bytes: &bytes[offset..],
+                    instruction: None,
+                    state,
+                });
+                break;
+            }
+        };
+        let line = Line {
+            addr: line_addr,
+            bytes: &bytes[offset..offset + instruction.len],
+            instruction: Some(instruction),
+            state,
+        };
+        state.apply(&instruction);
+        carry = match (instruction.mnemonic, carry) {
+            ("CLC", _) => Some(false),
+            ("SEC", _) => Some(true),
+            ("XCE", Some(carry)) => {
+                let was = state.emulation;
+                state.emulation = carry;
+                state.wide_math = false;
+                state.wide_index = false;
+                Some(was)
+            }
+            _ => None,
+        };
+        lines.push(line);
+        offset += instruction.len;
+    }
+    lines
+}
+
+impl fmt::Display for Line<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let hex: Vec<_> = self
+            .bytes
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        let text = match &self.instruction {
+            Some(instruction) => instruction.to_string(),
+            None => {
+                let values: Vec<_> = self
+                    .bytes
+                    .iter()
+                    .map(|byte| format!("${:02X}", byte))
+                    .collect();
+                format!(".db {}", values.join(","))
+            }
+        };
+        write!(
+            f,
+            "{:02X}:{:04X}  {:<12}{}",
+            self.addr >> 16,
+            self.addr & 0xFFFF,
+            hex.join(" "),
+            text
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(bytes: &[u8], state: State) -> String {
+        decode(bytes, 0x8000, state).unwrap().to_string()
+    }
+
+    #[test]
+    fn widths() {
+        let narrow = State::default();
+        let wide = State {
+            emulation: false,
+            wide_math: true,
+            wide_index: false,
+        };
+        assert_eq!(text(&[0xA9, 0x34, 0x12], narrow), "LDA #$34");
+        assert_eq!(text(&[0xA9, 0x34, 0x12], wide), "LDA #$1234");
+        assert_eq!(text(&[0xA2, 0x34, 0x12], wide), "LDX #$34");
+        assert_eq!(decode(&[0xA9, 0x34], 0x8000, wide), None);
+    }
+
+    #[test]
+    fn operands() {
+        let state = State::default();
+        assert_eq!(text(&[0xB1, 0x10], state), "LDA ($10),Y");
+        assert_eq!(text(&[0xBF, 0x00, 0x20, 0x7E], state), "LDA $7E2000,X");
+        assert_eq!(text(&[0x80, 0xFE], state), "BRA $8000");
+        assert_eq!(text(&[0xD0, 0x03], state), "BNE $8005");
+        assert_eq!(text(&[0x0A], state), "ASL A");
+        assert_eq!(text(&[0x54, 0x7E, 0x7F], state), "MVN $7F,$7E");
+    }
+
+    #[test]
+    fn rep_sep() {
+        let mut state = State::default();
+        state.apply(&decode(&[0xC2, 0x30], 0x8000, state).unwrap());
+        assert!(state.wide_math && state.wide_index);
+        state.apply(&decode(&[0xE2, 0x20], 0x8000, state).unwrap());
+        assert!(!state.wide_math && state.wide_index);
+    }
+
+    #[test]
+    fn xce() {
+        // SEC; XCE; LDA #$12; CLC; XCE; REP #$20; LDA #$1234
+        let bytes = [
+            0x38, 0xFB, 0xA9, 0x12, 0x18, 0xFB, 0xC2, 0x20, 0xA9, 0x34, 0x12,
+        ];
+        let lines = disassemble(&bytes, 0x8000, State::default());
+        let text: Vec<_> = lines.iter().map(|line| line.to_string()).collect();
+        assert_eq!(
+            text,
+            [
+                "00:8000  38          SEC",
+                "00:8001  FB          XCE",
+                "00:8002  A9 12       LDA #$12",
+                "00:8004  18          CLC",
+                "00:8005  FB          XCE",
+                "00:8006  C2 20       REP #$20",
+                "00:8008  A9 34 12    LDA #$1234",
+            ]
+        );
+        assert!(lines[2].state.emulation && !lines[5].state.emulation);
+        assert!(!lines[5].state.wide_math && lines[6].state.wide_math);
+        let lines = disassemble(&[0xA9, 0x12, 0x34], 0x8000, State::default());
+        assert_eq!(lines[1].to_string(), "00:8002  34          .db $34");
+    }
+}
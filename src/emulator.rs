@@ -0,0 +1,798 @@
+use super::ast::MemoryMap;
+use super::disasm::{self, Mode, State};
+
+pub const CARRY: u8 = 0x01;
+pub const ZERO: u8 = 0x02;
+pub const IRQ_DISABLE: u8 = 0x04;
+pub const DECIMAL: u8 = 0x08;
+pub const INDEX: u8 = 0x10;
+pub const MEMORY: u8 = 0x20;
+pub const OVERFLOW: u8 = 0x40;
+pub const NEGATIVE: u8 = 0x80;
+
+/// The 65816's registers
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Cpu {
+    pub a: u16,
+    pub x: u16,
+    pub y: u16,
+    pub s: u16,
+    pub d: u16,
+    pub db: u8,
+    pub pb: u8,
+    pub pc: u16,
+    pub p: u8,
+    pub emulation: bool,
+}
+
+impl Cpu {
+    pub fn flag(&self, flag: u8) -> bool {
+        self.p & flag != 0
+    }
+
+    /// The full 24-bit address of the next instruction
+    pub fn addr(&self) -> u32 {
+        ((self.pb as u32) << 16) | self.pc as u32
+    }
+
+    fn wide_math(&self) -> bool {
+        !self.emulation && !self.flag(MEMORY)
+    }
+
+    fn wide_index(&self) -> bool {
+        !self.emulation && !self.flag(INDEX)
+    }
+}
+
+/// What the CPU sees: the ROM, work RAM, and a stub for the registers
+/// from 0x2000 to 0x5FFF. The stub reads back whatever was last written,
+/// so tests can set registers up ahead of time.
+pub struct Bus {
+    rom: Vec<u8>,
+    map: MemoryMap,
+    pub wram: Vec<u8>,
+    pub io: Vec<u8>,
+    /// Every write to a register, in order
+    pub writes: Vec<(u16, u8)>,
+}
+
+enum Region {
+    Rom(usize),
+    Wram(usize),
+    Io(usize),
+    Open,
+}
+
+impl Bus {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Bus {
+            map: detect_map(&rom),
+            rom,
+            wram: vec![0; 0x20000],
+            io: vec![0; 0x4000],
+            writes: vec![],
+        }
+    }
+
+    pub fn read(&self, addr: u32) -> u8 {
+        match self.region(addr) {
+            Region::Rom(_) if self.rom.is_empty() => 0,
+            Region::Rom(offset) => self.rom[offset % self.rom.len()],
+            Region::Wram(offset) => self.wram[offset],
+            Region::Io(offset) => self.io[offset],
+            Region::Open => 0,
+        }
+    }
+
+    pub fn write(&mut self, addr: u32, value: u8) {
+        match self.region(addr) {
+            Region::Wram(offset) => self.wram[offset] = value,
+            Region::Io(offset) => {
+                self.io[offset] = value;
+                self.writes.push((0x2000 + offset as u16, value));
+            }
+            Region::Rom(_) | Region::Open => {}
+        }
+    }
+
+    pub fn read16(&self, addr: u32) -> u16 {
+        self.read(addr) as u16 | (self.read(addr.wrapping_add(1)) as u16) << 8
+    }
+
+    // Banks 0x00-0x3F and 0x80-0xBF all have the first 8KB of WRAM and
+    // the registers below the ROM. SRAM isn't emulated.
+    fn region(&self, addr: u32) -> Region {
+        let bank = (addr >> 16) as usize & 0xFF;
+        let offset = addr as usize & 0xFFFF;
+        let system = bank & 0x7F < 0x40;
+        let rom = match self.map {
+            _ if bank == 0x7E || bank == 0x7F => return Region::Wram(addr as usize - 0x7E0000),
+            _ if system && offset < 0x2000 => return Region::Wram(offset),
+            _ if system && offset < 0x6000 => return Region::Io(offset - 0x2000),
+            _ if system && offset < 0x8000 => return Region::Open,
+            MemoryMap::LoRom => ((bank & 0x7F) << 15) | (offset & 0x7FFF),
+            MemoryMap::HiRom => ((bank & 0x3F) << 16) | offset,
+            MemoryMap::ExHiRom if bank >= 0x80 => ((bank & 0x3F) << 16) | offset,
+            MemoryMap::ExHiRom => 0x400000 | ((bank & 0x3F) << 16) | offset,
+        };
+        Region::Rom(rom)
+    }
+}
+
+// Work out the memory map from the map mode in the header
+fn detect_map(rom: &[u8]) -> MemoryMap {
+    let mode = |offset: usize| rom.get(offset).map(|mode| mode & !0x10);
+    if mode(0x40FFD5) == Some(0x25) {
+        MemoryMap::ExHiRom
+    } else if mode(0xFFD5) == Some(0x21) {
+        MemoryMap::HiRom
+    } else {
+        MemoryMap::LoRom
+    }
+}
+
+/// Why the machine stopped running
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Stop {
+    /// It ran as many instructions as it was allowed to
+    Limit,
+    /// It got to the address it was run to
+    Reached,
+    /// The function it was calling returned
+    Returned,
+    /// A WDM instruction, along with its operand
+    Marker(u8),
+    /// An STP instruction
+    Stopped,
+    /// A WAI instruction. Nothing raises interrupts, so it'd be stuck.
+    Waiting,
+}
+
+enum Operand {
+    None,
+    Accumulator,
+    Immediate(u16),
+    Memory(u32),
+    Jump(u32),
+    Move(u8, u8),
+}
+
+/// A 65816 running a ROM image, for tests to check what a program does
+/// rather than which bytes it assembles to
+pub struct Machine {
+    pub cpu: Cpu,
+    pub bus: Bus,
+}
+
+impl Machine {
+    /// Load a ROM image and reset the CPU
+    pub fn new(image: Vec<u8>) -> Self {
+        let mut machine = Machine {
+            cpu: Cpu::default(),
+            bus: Bus::new(image),
+        };
+        machine.reset();
+        machine
+    }
+
+    /// Start again from the reset vector, in emulation mode
+    pub fn reset(&mut self) {
+        self.cpu = Cpu {
+            s: 0x01FF,
+            p: IRQ_DISABLE | INDEX | MEMORY,
+            pc: self.bus.read16(0xFFFC),
+            emulation: true,
+            ..Cpu::default()
+        };
+    }
+
+    /// Run for at most `limit` instructions
+    pub fn run(&mut self, limit: usize) -> Stop {
+        for _ in 0..limit {
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Run until the next instruction is at `addr`
+    pub fn run_to(&mut self, addr: u32, limit: usize) -> Stop {
+        for _ in 0..limit {
+            if self.cpu.addr() == addr {
+                return Stop::Reached;
+            }
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Jump to the function at `addr` in the current mode, and run until
+    /// it returns. The return itself isn't run, since nothing called it.
+    pub fn call(&mut self, addr: u32, limit: usize) -> Stop {
+        let depth = self.cpu.s;
+        self.cpu.pb = (addr >> 16) as u8;
+        self.cpu.pc = addr as u16;
+        for _ in 0..limit {
+            let opcode = self.bus.read(self.cpu.addr());
+            if matches!(opcode, 0x40 | 0x60 | 0x6B) && self.cpu.s == depth {
+                return Stop::Returned;
+            }
+            if let Some(stop) = self.step() {
+                return stop;
+            }
+        }
+        Stop::Limit
+    }
+
+    /// Run one instruction
+    pub fn step(&mut self) -> Option<Stop> {
+        let addr = self.cpu.addr();
+        let bytes: Vec<u8> = (0..4)
+            .map(|i| self.bus.read((addr & 0xFF_0000) | (addr + i) & 0xFFFF))
+            .collect();
+        let state = State {
+            emulation: self.cpu.emulation,
+            wide_math: !self.cpu.flag(MEMORY),
+            wide_index: !self.cpu.flag(INDEX),
+        };
+        // Nothing is longer than 4 bytes, so this always decodes
+        let instruction = disasm::decode(&bytes, addr, state)?;
+        self.cpu.pc = self.cpu.pc.wrapping_add(instruction.len as u16);
+        let operand = self.operand(instruction.mnemonic, instruction.operand);
+        self.execute(instruction.mnemonic, operand)
+    }
+
+    fn read24(&self, addr: u32) -> u32 {
+        self.bus.read16(addr) as u32 | (self.bus.read(addr.wrapping_add(2)) as u32) << 16
+    }
+
+    // Work out the address an instruction refers to
+    fn operand(&self, mnemonic: &str, operand: disasm::Operand) -> Operand {
+        let cpu = self.cpu;
+        let (x, y) = (cpu.x as u32, cpu.y as u32);
+        let direct = |offset: u32| (cpu.d as u32 + offset) & 0xFFFF;
+        let data = |addr: u32| (((cpu.db as u32) << 16) + addr) & 0xFF_FFFF;
+        let program = |addr: u32| ((cpu.pb as u32) << 16) | (addr & 0xFFFF);
+        let jump = matches!(mnemonic, "JMP" | "JML" | "JSR" | "JSL");
+        let (mode, value) = match operand {
+            disasm::Operand::None => return Operand::None,
+            disasm::Operand::Accumulator => return Operand::Accumulator,
+            disasm::Operand::Immediate(value) => return Operand::Immediate(value as u16),
+            disasm::Operand::Target(target) => return Operand::Jump(target),
+            disasm::Operand::BlockMove(dest, src) => return Operand::Move(dest, src),
+            disasm::Operand::Address(mode, value) => (mode, value),
+        };
+        match mode {
+            Mode::Direct => Operand::Memory(direct(value)),
+            Mode::DirectX => Operand::Memory(direct(value + x)),
+            Mode::DirectY => Operand::Memory(direct(value + y)),
+            Mode::DirectIndirect => {
+                let pointer = self.bus.read16(direct(value)) as u32;
+                match mnemonic {
+                    "PEI" => Operand::Immediate(pointer as u16),
+                    _ => Operand::Memory(data(pointer)),
+                }
+            }
+            Mode::DirectIndirectX => {
+                Operand::Memory(data(self.bus.read16(direct(value + x)) as u32))
+            }
+            Mode::DirectIndirectY => {
+                Operand::Memory(data(self.bus.read16(direct(value)) as u32 + y))
+            }
+            Mode::DirectIndirectLong => Operand::Memory(self.read24(direct(value))),
+            Mode::DirectIndirectLongY => {
+                Operand::Memory((self.read24(direct(value)) + y) & 0xFF_FFFF)
+            }
+            Mode::Absolute if jump => Operand::Jump(program(value)),
+            Mode::Absolute => Operand::Memory(data(value)),
+            Mode::AbsoluteX => Operand::Memory(data(value + x)),
+            Mode::AbsoluteY => Operand::Memory(data(value + y)),
+            Mode::AbsoluteIndirect => Operand::Jump(program(self.bus.read16(value) as u32)),
+            Mode::AbsoluteIndirectX => {
+                Operand::Jump(program(self.bus.read16(program(value + x)) as u32))
+            }
+            Mode::AbsoluteIndirectLong => Operand::Jump(self.read24(value)),
+            Mode::Long if jump => Operand::Jump(value),
+            Mode::Long => Operand::Memory(value),
+            Mode::LongX => Operand::Memory((value + x) & 0xFF_FFFF),
+            Mode::Stack => Operand::Memory((cpu.s as u32 + value) & 0xFFFF),
+            Mode::StackIndirectY => {
+                let pointer = self.bus.read16((cpu.s as u32 + value) & 0xFFFF) as u32;
+                Operand::Memory(data(pointer + y))
+            }
+            _ => Operand::None,
+        }
+    }
+
+    fn execute(&mut self, mnemonic: &str, operand: Operand) -> Option<Stop> {
+        let wide_math = self.cpu.wide_math();
+        let wide_index = self.cpu.wide_index();
+        let (a, x, y) = (self.cpu.a, self.cpu.x, self.cpu.y);
+        match mnemonic {
+            "LDA" => {
+                let value = self.load(&operand, wide_math);
+                self.set_a(value);
+            }
+            "LDX" => {
+                self.cpu.x = self.load(&operand, wide_index);
+                self.set_nz(self.cpu.x, wide_index);
+            }
+            "LDY" => {
+                self.cpu.y = self.load(&operand, wide_index);
+                self.set_nz(self.cpu.y, wide_index);
+            }
+            "STA" => self.store(&operand, a, wide_math),
+            "STX" => self.store(&operand, x, wide_index),
+            "STY" => self.store(&operand, y, wide_index),
+            "STZ" => self.store(&operand, 0, wide_math),
+            "ADC" => {
+                let value = self.load(&operand, wide_math);
+                self.add(value, false);
+            }
+            "SBC" => {
+                let value = self.load(&operand, wide_math);
+                self.add(value, true);
+            }
+            "AND" => {
+                let value = self.load(&operand, wide_math);
+                self.set_a(a & value);
+            }
+            "ORA" => {
+                let value = self.load(&operand, wide_math);
+                self.set_a(a | value);
+            }
+            "EOR" => {
+                let value = self.load(&operand, wide_math);
+                self.set_a(a ^ value);
+            }
+            "CMP" => {
+                let value = self.load(&operand, wide_math);
+                self.compare(a, value, wide_math);
+            }
+            "CPX" => {
+                let value = self.load(&operand, wide_index);
+                self.compare(x, value, wide_index);
+            }
+            "CPY" => {
+                let value = self.load(&operand, wide_index);
+                self.compare(y, value, wide_index);
+            }
+            "BIT" => {
+                let value = self.load(&operand, wide_math);
+                let sign = sign(wide_math);
+                self.set_flag(ZERO, a & value & mask(wide_math) == 0);
+                if !matches!(operand, Operand::Immediate(_)) {
+                    self.set_flag(NEGATIVE, value & sign != 0);
+                    self.set_flag(OVERFLOW, value & (sign >> 1) != 0);
+                }
+            }
+            "ASL" | "LSR" | "ROL" | "ROR" | "INC" | "DEC" | "TSB" | "TRB" => {
+                self.modify(mnemonic, &operand, wide_math)
+            }
+            "INX" => self.set_x(x.wrapping_add(1)),
+            "DEX" => self.set_x(x.wrapping_sub(1)),
+            "INY" => self.set_y(y.wrapping_add(1)),
+            "DEY" => self.set_y(y.wrapping_sub(1)),
+            "BPL" | "BMI" | "BVC" | "BVS" | "BCC" | "BCS" | "BNE" | "BEQ" => {
+                let (flag, set) = match mnemonic {
+                    "BPL" => (NEGATIVE, false),
+                    "BMI" => (NEGATIVE, true),
+                    "BVC" => (OVERFLOW, false),
+                    "BVS" => (OVERFLOW, true),
+                    "BCC" => (CARRY, false),
+                    "BCS" => (CARRY, true),
+                    "BNE" => (ZERO, false),
+                    _ => (ZERO, true),
+                };
+                if self.cpu.flag(flag) == set {
+                    self.jump(&operand);
+                }
+            }
+            "BRA" | "BRL" | "JMP" | "JML" => self.jump(&operand),
+            "JSR" => {
+                self.push16(self.cpu.pc.wrapping_sub(1));
+                self.jump(&operand);
+            }
+            "JSL" => {
+                self.push8(self.cpu.pb);
+                self.push16(self.cpu.pc.wrapping_sub(1));
+                self.jump(&operand);
+            }
+            "RTS" => self.cpu.pc = self.pull16().wrapping_add(1),
+            "RTL" => {
+                self.cpu.pc = self.pull16().wrapping_add(1);
+                self.cpu.pb = self.pull8();
+            }
+            "RTI" => {
+                let p = self.pull8();
+                self.set_p(p);
+                self.cpu.pc = self.pull16();
+                if !self.cpu.emulation {
+                    self.cpu.pb = self.pull8();
+                }
+            }
+            "BRK" | "COP" => self.interrupt(mnemonic == "BRK"),
+            "PHA" => self.push(a, wide_math),
+            "PHX" => self.push(x, wide_index),
+            "PHY" => self.push(y, wide_index),
+            "PLA" => {
+                let value = self.pull(wide_math);
+                self.set_a(value);
+            }
+            "PLX" => {
+                let value = self.pull(wide_index);
+                self.set_x(value);
+            }
+            "PLY" => {
+                let value = self.pull(wide_index);
+                self.set_y(value);
+            }
+            "PHP" => self.push8(self.cpu.p),
+            "PLP" => {
+                let p = self.pull8();
+                self.set_p(p);
+            }
+            "PHB" => self.push8(self.cpu.db),
+            "PLB" => {
+                self.cpu.db = self.pull8();
+                self.set_nz(self.cpu.db as u16, false);
+            }
+            "PHD" => self.push16(self.cpu.d),
+            "PLD" => {
+                self.cpu.d = self.pull16();
+                self.set_nz(self.cpu.d, true);
+            }
+            "PHK" => self.push8(self.cpu.pb),
+            "PEA" | "PEI" => {
+                let value = self.load(&operand, true);
+                self.push16(value);
+            }
+            "PER" => {
+                if let Operand::Jump(target) = operand {
+                    self.push16(target as u16);
+                }
+            }
+            "TAX" => self.set_x(a),
+            "TAY" => self.set_y(a),
+            "TXA" => self.set_a(x),
+            "TYA" => self.set_a(y),
+            "TXY" => self.set_y(x),
+            "TYX" => self.set_x(y),
+            "TSX" => self.set_x(self.cpu.s),
+            "TXS" => self.set_s(x),
+            "TCS" => self.set_s(a),
+            "TSC" => {
+                self.cpu.a = self.cpu.s;
+                self.set_nz(self.cpu.a, true);
+            }
+            "TCD" => {
+                self.cpu.d = a;
+                self.set_nz(a, true);
+            }
+            "TDC" => {
+                self.cpu.a = self.cpu.d;
+                self.set_nz(self.cpu.a, true);
+            }
+            "XBA" => {
+                self.cpu.a = a.swap_bytes();
+                self.set_nz(self.cpu.a, false);
+            }
+            "CLC" => self.set_flag(CARRY, false),
+            "SEC" => self.set_flag(CARRY, true),
+            "CLI" => self.set_flag(IRQ_DISABLE, false),
+            "SEI" => self.set_flag(IRQ_DISABLE, true),
+            "CLD" => self.set_flag(DECIMAL, false),
+            "SED" => self.set_flag(DECIMAL, true),
+            "CLV" => self.set_flag(OVERFLOW, false),
+            "REP" => {
+                let bits = self.load(&operand, false) as u8;
+                self.set_p(self.cpu.p & !bits);
+            }
+            "SEP" => {
+                let bits = self.load(&operand, false) as u8;
+                self.set_p(self.cpu.p | bits);
+            }
+            "XCE" => {
+                let carry = self.cpu.flag(CARRY);
+                self.set_flag(CARRY, self.cpu.emulation);
+                self.cpu.emulation = carry;
+                self.set_p(self.cpu.p);
+                self.set_s(self.cpu.s);
+            }
+            "MVN" | "MVP" => {
+                if let Operand::Move(dest, src) = operand {
+                    let value = self.bus.read(((src as u32) << 16) | x as u32);
+                    self.bus.write(((dest as u32) << 16) | y as u32, value);
+                    self.cpu.db = dest;
+                    let step = if mnemonic == "MVN" { 1 } else { 0xFFFF };
+                    self.set_index(x.wrapping_add(step), y.wrapping_add(step));
+                    self.cpu.a = a.wrapping_sub(1);
+                    // Keep going until the count runs out
+                    if self.cpu.a != 0xFFFF {
+                        self.cpu.pc = self.cpu.pc.wrapping_sub(3);
+                    }
+                }
+            }
+            "NOP" => {}
+            "WDM" => return Some(Stop::Marker(self.load(&operand, false) as u8)),
+            "STP" | "WAI" => {
+                // Stay put, so running again stops again
+                self.cpu.pc = self.cpu.pc.wrapping_sub(1);
+                return Some(match mnemonic {
+                    "STP" => Stop::Stopped,
+                    _ => Stop::Waiting,
+                });
+            }
+            _ => unreachable!("no such instruction {}", mnemonic),
+        }
+        None
+    }
+
+    fn load(&mut self, operand: &Operand, wide: bool) -> u16 {
+        let value = match *operand {
+            Operand::Immediate(value) => value,
+            Operand::Accumulator => self.cpu.a,
+            Operand::Memory(addr) if wide => self.bus.read16(addr),
+            Operand::Memory(addr) => self.bus.read(addr) as u16,
+            _ => 0,
+        };
+        value & mask(wide)
+    }
+
+    fn store(&mut self, operand: &Operand, value: u16, wide: bool) {
+        match *operand {
+            Operand::Accumulator if wide => self.cpu.a = value,
+            Operand::Accumulator => self.cpu.a = (self.cpu.a & 0xFF00) | (value & 0xFF),
+            Operand::Memory(addr) => {
+                self.bus.write(addr, value as u8);
+                if wide {
+                    self.bus.write(addr.wrapping_add(1), (value >> 8) as u8);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn jump(&mut self, operand: &Operand) {
+        if let Operand::Jump(target) = *operand {
+            self.cpu.pb = (target >> 16) as u8;
+            self.cpu.pc = target as u16;
+        }
+    }
+
+    // ADC, or SBC when `subtract` is set. Decimal mode works a digit at a
+    // time, but sets overflow the same way as binary.
+    fn add(&mut self, value: u16, subtract: bool) {
+        let wide = self.cpu.wide_math();
+        let a = (self.cpu.a & mask(wide)) as u32;
+        let operand = match subtract {
+            true => !value & mask(wide),
+            false => value,
+        } as u32;
+        let mut carry = self.cpu.flag(CARRY) as u32;
+        let mut result = 0;
+        if self.cpu.flag(DECIMAL) {
+            for shift in (0..if wide { 16 } else { 8 }).step_by(4) {
+                let digit = ((a >> shift) & 0xF) + ((operand >> shift) & 0xF) + carry;
+                let (digit, next) = match (subtract, digit) {
+                    (false, 10..) => (digit + 6, 1),
+                    (true, 0..=15) => (digit.wrapping_sub(6), 0),
+                    (_, _) => (digit, (digit > 15) as u32),
+                };
+                result |= (digit & 0xF) << shift;
+                carry = next;
+            }
+        } else {
+            result = a + operand + carry;
+            carry = (result > mask(wide) as u32) as u32;
+        }
+        let result = result & mask(wide) as u32;
+        let overflow = !(a ^ operand) & (a ^ result) & sign(wide) as u32;
+        self.set_flag(CARRY, carry != 0);
+        self.set_flag(OVERFLOW, overflow != 0);
+        self.set_a(result as u16);
+    }
+
+    fn compare(&mut self, register: u16, value: u16, wide: bool) {
+        let register = register & mask(wide);
+        self.set_flag(CARRY, register >= value);
+        self.set_nz(register.wrapping_sub(value), wide);
+    }
+
+    // Read-modify-write instructions, on memory or A
+    fn modify(&mut self, mnemonic: &str, operand: &Operand, wide: bool) {
+        let value = self.load(operand, wide);
+        let sign = sign(wide);
+        let carry = self.cpu.flag(CARRY) as u16;
+        let a = self.cpu.a & mask(wide);
+        let result = match mnemonic {
+            "ASL" => {
+                self.set_flag(CARRY, value & sign != 0);
+                value << 1
+            }
+            "LSR" => {
+                self.set_flag(CARRY, value & 1 != 0);
+                value >> 1
+            }
+            "ROL" => {
+                self.set_flag(CARRY, value & sign != 0);
+                (value << 1) | carry
+            }
+            "ROR" => {
+                self.set_flag(CARRY, value & 1 != 0);
+                (value >> 1) | (carry * sign)
+            }
+            "INC" => value.wrapping_add(1),
+            "DEC" => value.wrapping_sub(1),
+            "TSB" => value | a,
+            _ => value & !a,
+        } & mask(wide);
+        match mnemonic {
+            "TSB" | "TRB" => self.set_flag(ZERO, value & a == 0),
+            _ => self.set_nz(result, wide),
+        }
+        self.store(operand, result, wide);
+    }
+
+    fn interrupt(&mut self, brk: bool) {
+        let vector = match (self.cpu.emulation, brk) {
+            (false, true) => 0xFFE6,
+            (false, false) => 0xFFE4,
+            (true, true) => 0xFFFE,
+            (true, false) => 0xFFF4,
+        };
+        if !self.cpu.emulation {
+            self.push8(self.cpu.pb);
+        }
+        self.push16(self.cpu.pc);
+        self.push8(self.cpu.p);
+        self.set_flag(IRQ_DISABLE, true);
+        self.set_flag(DECIMAL, false);
+        self.cpu.pb = 0;
+        self.cpu.pc = self.bus.read16(vector);
+    }
+
+    fn push8(&mut self, value: u8) {
+        self.bus.write(self.cpu.s as u32, value);
+        self.set_s(self.cpu.s.wrapping_sub(1));
+    }
+
+    fn push16(&mut self, value: u16) {
+        self.push8((value >> 8) as u8);
+        self.push8(value as u8);
+    }
+
+    fn push(&mut self, value: u16, wide: bool) {
+        match wide {
+            true => self.push16(value),
+            false => self.push8(value as u8),
+        }
+    }
+
+    fn pull8(&mut self) -> u8 {
+        self.set_s(self.cpu.s.wrapping_add(1));
+        self.bus.read(self.cpu.s as u32)
+    }
+
+    fn pull16(&mut self) -> u16 {
+        self.pull8() as u16 | (self.pull8() as u16) << 8
+    }
+
+    fn pull(&mut self, wide: bool) -> u16 {
+        match wide {
+            true => self.pull16(),
+            false => self.pull8() as u16,
+        }
+    }
+
+    fn set_flag(&mut self, flag: u8, set: bool) {
+        match set {
+            true => self.cpu.p |= flag,
+            false => self.cpu.p &= !flag,
+        }
+    }
+
+    fn set_nz(&mut self, value: u16, wide: bool) {
+        self.set_flag(ZERO, value & mask(wide) == 0);
+        self.set_flag(NEGATIVE, value & sign(wide) != 0);
+    }
+
+    // Emulation mode forces M and X, and narrow index registers lose
+    // their high byte
+    fn set_p(&mut self, p: u8) {
+        self.cpu.p = p;
+        if self.cpu.emulation {
+            self.cpu.p |= INDEX | MEMORY;
+        }
+        self.set_index(self.cpu.x, self.cpu.y);
+    }
+
+    fn set_index(&mut self, x: u16, y: u16) {
+        let mask = mask(self.cpu.wide_index());
+        self.cpu.x = x & mask;
+        self.cpu.y = y & mask;
+    }
+
+    fn set_s(&mut self, s: u16) {
+        self.cpu.s = match self.cpu.emulation {
+            true => 0x0100 | (s & 0xFF),
+            false => s,
+        };
+    }
+
+    fn set_a(&mut self, value: u16) {
+        let wide = self.cpu.wide_math();
+        self.store(&Operand::Accumulator, value, wide);
+        self.set_nz(value, wide);
+    }
+
+    fn set_x(&mut self, value: u16) {
+        self.set_index(value, self.cpu.y);
+        self.set_nz(value, self.cpu.wide_index());
+    }
+
+    fn set_y(&mut self, value: u16) {
+        self.set_index(self.cpu.x, value);
+        self.set_nz(value, self.cpu.wide_index());
+    }
+}
+
+fn mask(wide: bool) -> u16 {
+    match wide {
+        true => 0xFFFF,
+        false => 0xFF,
+    }
+}
+
+fn sign(wide: bool) -> u16 {
+    match wide {
+        true => 0x8000,
+        false => 0x80,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A LoROM image running `code` from reset
+    fn boot(code: &[u8]) -> Machine {
+        let mut image = vec![0; 0x8000];
+        image[..code.len()].copy_from_slice(code);
+        image[0x7FFC] = 0x00;
+        image[0x7FFD] = 0x80;
+        Machine::new(image)
+    }
+
+    #[test]
+    fn decimal() {
+        // SED; CLC; LDA #$19; ADC #$28; WDM #1
+        let mut machine = boot(&[0xF8, 0x18, 0xA9, 0x19, 0x69, 0x28, 0x42, 0x01]);
+        assert_eq!(machine.run(10), Stop::Marker(1));
+        assert_eq!(machine.cpu.a, 0x47);
+        assert!(!machine.cpu.flag(CARRY));
+
+        // SED; SEC; LDA #$47; SBC #$48; STP
+        let mut machine = boot(&[0xF8, 0x38, 0xA9, 0x47, 0xE9, 0x48, 0xDB]);
+        assert_eq!(machine.run(10), Stop::Stopped);
+        assert_eq!(machine.cpu.a, 0x99);
+        assert!(!machine.cpu.flag(CARRY));
+        assert_eq!(machine.cpu.pc, 0x8006);
+    }
+
+    #[test]
+    fn block_move() {
+        // CLC; XCE; REP #$30; LDA #$0002; LDX #$8000; LDY #$0100; MVN $7E,$00
+        let mut machine = boot(&[
+            0x18, 0xFB, 0xC2, 0x30, 0xA9, 0x02, 0x00, 0xA2, 0x00, 0x80, 0xA0, 0x00, 0x01, 0x54,
+            0x7E, 0x00, 0xDB,
+        ]);
+        assert_eq!(machine.run(100), Stop::Stopped);
+        assert_eq!(&machine.bus.wram[0x100..0x104], &[0x18, 0xFB, 0xC2, 0x00]);
+        assert_eq!(machine.cpu.a, 0xFFFF);
+        assert_eq!(machine.cpu.db, 0x7E);
+    }
+}
@@ -1,3 +1,9 @@
+pub mod assembly;
 pub mod ast;
 pub mod codegen;
+pub mod diagnostic;
+pub mod disasm;
+pub mod emulator;
+pub mod listing;
 pub mod parser;
+pub mod symbols;
@@ -0,0 +1,54 @@
+use super::codegen::Rom;
+use super::diagnostic::location;
+use super::disasm::{self, Line};
+use std::fmt::Write;
+
+// Bytes of data shown on each line
+const DATA_WIDTH: usize = 4;
+
+/// Write out every instruction in the ROM with its address, raw bytes,
+/// disassembly and the line of source it came from
+pub fn write(rom: &Rom, source: &str) -> String {
+    let mut output = String::new();
+    let mut owner = None;
+    let mut line = None;
+    for chunk in &rom.chunks {
+        if owner != Some(chunk.owner) {
+            if owner.is_some() {
+                output.push('\n');
+            }
+            writeln!(output, "{}:", chunk.owner).unwrap();
+            owner = Some(chunk.owner);
+        }
+        // Only show the source when moving on to another line of it
+        let mut comment = match chunk.span.and_then(|span| location(source, span.0)) {
+            Some((number, _)) if line != Some(number) => {
+                line = Some(number);
+                let text = source.lines().nth(number - 1).unwrap_or("").trim();
+                format!("; {}: {}", number, text)
+            }
+            _ => String::new(),
+        };
+        let bytes = &rom.image[chunk.offset..chunk.offset + chunk.len];
+        let addr = chunk.addr as u32;
+        let lines = match chunk.data {
+            true => bytes
+                .chunks(DATA_WIDTH)
+                .enumerate()
+                .map(|(i, bytes)| Line {
+                    addr: addr + (i * DATA_WIDTH) as u32,
+                    bytes,
+                    instruction: None,
+                    state: chunk.state,
+                })
+                .collect(),
+            false => disasm::disassemble(bytes, addr, chunk.state),
+        };
+        for line in lines {
+            let text = format!("{:<41}{}", line.to_string(), comment);
+            writeln!(output, "{}", text.trim_end()).unwrap();
+            comment.clear();
+        }
+    }
+    output
+}
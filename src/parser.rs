@@ -2,10 +2,10 @@ use nom::{
     branch::alt,
     bytes::complete::{is_a, tag, tag_no_case, take_until},
     character::complete::{digit1, hex_digit1, multispace0, oct_digit1},
-    combinator::{all_consuming, complete, cut, map, opt, value},
+    combinator::{all_consuming, complete, cut, map, not, opt, value},
     error::context,
     multi::{many0, separated_list},
-    sequence::{delimited, pair, preceded, separated_pair, terminated},
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
 };
 
 use super::ast::*;
@@ -13,23 +13,31 @@ use super::ast::*;
 pub type Error<'a> = nom::Err<nom::error::VerboseError<&'a str>>;
 type IResult<'a, T> = Result<(&'a str, T), Error<'a>>;
 
-pub fn program(input: &str) -> Result<Program, nom::Err<nom::error::VerboseError<&str>>> {
+pub fn program(input: &str) -> Result<Program<'_>, nom::Err<nom::error::VerboseError<&str>>> {
     let (_, definitions) =
         complete(all_consuming(terminated(many0(definition), multispace0)))(input)?;
     Ok(Program { definitions })
 }
 
-fn definition(input: &str) -> IResult<Definition> {
+fn definition(input: &str) -> IResult<'_, Definition<'_>> {
     context(
         "definition",
         alt((
             preceded(ws(tag("FUN")), map(cut(function), Definition::Function)),
             preceded(ws(tag("VAR")), map(cut(var), Definition::Var)),
+            map(pair(ws(tag("HEADER")), cut(header)), |(keyword, fields)| {
+                Definition::Header(Header {
+                    fields,
+                    span: Span(keyword),
+                })
+            }),
+            preceded(ws(tag("DATA")), map(cut(data), Definition::Data)),
+            preceded(ws(tag("INCBIN")), map(cut(incbin), Definition::Data)),
         )),
     )(input)
 }
 
-fn function(input: &str) -> IResult<Function> {
+fn function(input: &str) -> IResult<'_, Function<'_>> {
     context(
         "function",
         map(pair(ws(identifier), block), |(name, body)| Function {
@@ -39,7 +47,7 @@ fn function(input: &str) -> IResult<Function> {
     )(input)
 }
 
-fn var(input: &str) -> IResult<Var> {
+fn var(input: &str) -> IResult<'_, Var<'_>> {
     map(
         terminated(
             separated_pair(ws(identifier), ws(tag(":=")), ws(number)),
@@ -49,7 +57,99 @@ fn var(input: &str) -> IResult<Var> {
     )(input)
 }
 
-fn block(input: &str) -> IResult<Block> {
+fn data(input: &str) -> IResult<'_, Data<'_>> {
+    context(
+        "data",
+        map(
+            tuple((
+                opt(ws(width)),
+                ws(identifier),
+                attributes,
+                delimited(
+                    ws(tag("{")),
+                    separated_list(ws(tag(",")), ws(number)),
+                    pair(opt(ws(tag(","))), ws(tag("}"))),
+                ),
+            )),
+            |(width, name, attributes, values)| Data {
+                attributes,
+                contents: DataContents::Table(width.unwrap_or(Width::Byte), values),
+                name,
+            },
+        ),
+    )(input)
+}
+
+fn incbin(input: &str) -> IResult<'_, Data<'_>> {
+    context(
+        "incbin",
+        map(
+            terminated(
+                tuple((ws(identifier), attributes, ws(string))),
+                ws(tag(";")),
+            ),
+            |(name, attributes, path)| Data {
+                attributes,
+                contents: DataContents::Binary(path),
+                name,
+            },
+        ),
+    )(input)
+}
+
+fn width(input: &str) -> IResult<'_, Width> {
+    alt((
+        value(Width::Byte, tag("BYTE")),
+        value(Width::Word, tag("WORD")),
+        value(Width::Long, tag("LONG")),
+    ))(input)
+}
+
+fn header(input: &str) -> IResult<'_, Vec<HeaderItem<'_>>> {
+    context(
+        "header",
+        delimited(ws(tag("{")), many0(header_field), ws(tag("}"))),
+    )(input)
+}
+
+// Fields keep the source text they came from, like statements do
+fn header_field(input: &str) -> IResult<'_, HeaderItem<'_>> {
+    let (start, _) = comment(input)?;
+    let (rest, field) = context(
+        "header_field",
+        terminated(
+            alt((
+                map(header_value("title", string), HeaderField::Title),
+                map(header_value("map", memory_map), HeaderField::MemoryMap),
+                map(header_value("region", number), HeaderField::Region),
+                map(header_value("rom_size", number), HeaderField::RomSize),
+                map(header_value("sram_size", number), HeaderField::SramSize),
+                map(header_value("version", number), HeaderField::Version),
+                map(header_value("developer", number), HeaderField::Developer),
+            )),
+            ws(tag(";")),
+        ),
+    )(start)?;
+    let span = Span(&start[..start.len() - rest.len()]);
+    Ok((rest, HeaderItem { field, span }))
+}
+
+fn header_value<'a, T, F: Fn(&'a str) -> IResult<'a, T>>(
+    name: &'static str,
+    combinator: F,
+) -> impl Fn(&'a str) -> IResult<'a, T> {
+    preceded(pair(ws(tag(name)), ws(tag(":="))), ws(combinator))
+}
+
+fn memory_map(input: &str) -> IResult<'_, MemoryMap> {
+    alt((
+        value(MemoryMap::LoRom, tag("LOROM")),
+        value(MemoryMap::HiRom, tag("HIROM")),
+        value(MemoryMap::ExHiRom, tag("EXHIROM")),
+    ))(input)
+}
+
+fn block(input: &str) -> IResult<'_, Block<'_>> {
     context(
         "block",
         map(
@@ -59,7 +159,7 @@ fn block(input: &str) -> IResult<Block> {
                     "body",
                     delimited(
                         context("open", ws(tag("{"))),
-                        context("inner", many0(instruction)),
+                        context("inner", many0(located(instruction))),
                         context("close", ws(tag("}"))),
                     ),
                 ),
@@ -72,7 +172,7 @@ fn block(input: &str) -> IResult<Block> {
     )(input)
 }
 
-fn attributes(input: &str) -> IResult<Vec<Attribute>> {
+fn attributes(input: &str) -> IResult<'_, Vec<Attribute>> {
     context(
         "attributes",
         map(
@@ -86,8 +186,15 @@ fn attributes(input: &str) -> IResult<Vec<Attribute>> {
     )(input)
 }
 
-fn attribute(input: &str) -> IResult<Attribute> {
+fn attribute(input: &str) -> IResult<'_, Attribute> {
     alt((
+        map(
+            preceded(
+                tag("BANK"),
+                delimited(ws(tag("(")), ws(number), ws(tag(")"))),
+            ),
+            Attribute::Bank,
+        ),
         value(Attribute::Emulation, tag("EMU")),
         value(Attribute::Extern, tag("EXTERN")),
         value(Attribute::Interrupt, tag("INTR")),
@@ -99,7 +206,20 @@ fn attribute(input: &str) -> IResult<Attribute> {
     ))(input)
 }
 
-fn instruction(input: &str) -> IResult<Instruction> {
+// Keep track of the source text an instruction came from, so errors
+// can point back at it
+fn located<'a, F: Fn(&'a str) -> IResult<'a, Instruction<'a>>>(
+    parser: F,
+) -> impl Fn(&'a str) -> IResult<'a, Statement<'a>> {
+    move |input| {
+        let (start, _) = comment(input)?;
+        let (rest, instruction) = parser(start)?;
+        let span = Span(&start[..start.len() - rest.len()]);
+        Ok((rest, Statement { instruction, span }))
+    }
+}
+
+fn instruction(input: &str) -> IResult<'_, Instruction<'_>> {
     context(
         "instruction",
         alt((
@@ -108,83 +228,281 @@ fn instruction(input: &str) -> IResult<Instruction> {
                     assign,
                     and_assign,
                     or_assign,
+                    add_assign,
+                    add_carry_assign,
+                    sub_assign,
+                    sub_carry_assign,
+                    xor_assign,
+                    shift_left_assign,
+                    shift_right_assign,
+                    rotate_left_assign,
+                    rotate_right_assign,
+                    increment,
+                    decrement,
                     call,
                     push,
                     pop,
-                    value(Instruction::Sei, ws(tag("SEI"))),
-                    value(Instruction::Cli, ws(tag("CLI"))),
+                    alt((
+                        value(Instruction::Sei, ws(tag("SEI"))),
+                        value(Instruction::Cli, ws(tag("CLI"))),
+                        value(Instruction::Break, ws(tag("BREAK"))),
+                        value(Instruction::Continue, ws(tag("CONTINUE"))),
+                        swap,
+                        bytes,
+                    )),
                 )),
                 ws(tag(";")),
             ),
             do_loop,
+            while_loop,
+            for_loop,
             if_block,
             map(block, Instruction::Block),
         )),
     )(input)
 }
 
-fn do_loop(input: &str) -> IResult<Instruction> {
+fn do_loop(input: &str) -> IResult<'_, Instruction<'_>> {
     map(
         preceded(
             ws(tag("DO")),
-            pair(block, opt(preceded(ws(tag("WHILE")), conditional))),
+            pair(
+                block,
+                // A WHILE straight after a DO block always belongs to it,
+                // so it can't have a body of its own as well
+                opt(preceded(
+                    ws(tag("WHILE")),
+                    terminated(conditional, cut(context("do_while", not(ws(tag("{")))))),
+                )),
+            ),
         ),
         |(block, cond)| Instruction::Loop(block, cond),
     )(input)
 }
 
-fn if_block(input: &str) -> IResult<Instruction> {
+fn while_loop(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        preceded(ws(tag("WHILE")), pair(conditional, block)),
+        |(cond, block)| Instruction::While(block, cond),
+    )(input)
+}
+
+fn for_loop(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        preceded(
+            ws(tag("FOR")),
+            tuple((
+                ws(index),
+                preceded(ws(tag(":=")), ws(number)),
+                preceded(ws(tag("TO")), ws(number)),
+                block,
+            )),
+        ),
+        |(reg, from, to, block)| Instruction::For(block, reg, from, to),
+    )(input)
+}
+
+fn if_block(input: &str) -> IResult<'_, Instruction<'_>> {
     map(
-        preceded(ws(tag("IF")), pair(conditional, block)),
-        |(cond, block)| Instruction::If(block, cond),
+        preceded(
+            ws(tag("IF")),
+            tuple((
+                conditional,
+                block,
+                opt(preceded(ws(tag("ELSE")), else_block)),
+            )),
+        ),
+        |(cond, block, else_block)| Instruction::If(block, cond, else_block),
     )(input)
 }
 
-fn assign(input: &str) -> IResult<Instruction> {
+// An ELSE IF is just an ELSE block holding nothing but another IF
+fn else_block(input: &str) -> IResult<'_, Block<'_>> {
+    alt((
+        map(located(if_block), |statement| Block {
+            attributes: vec![],
+            instructions: vec![statement],
+        }),
+        block,
+    ))(input)
+}
+
+fn assign(input: &str) -> IResult<'_, Instruction<'_>> {
     map(
         separated_pair(ws(operand), ws(tag(":=")), ws(operand)),
         |(l, r)| Instruction::Assign(l, r),
     )(input)
 }
 
-fn and_assign(input: &str) -> IResult<Instruction> {
+fn and_assign(input: &str) -> IResult<'_, Instruction<'_>> {
     map(
         separated_pair(ws(operand), ws(tag("&=")), ws(operand)),
         |(l, r)| Instruction::AndAssign(l, r),
     )(input)
 }
 
-fn or_assign(input: &str) -> IResult<Instruction> {
+fn or_assign(input: &str) -> IResult<'_, Instruction<'_>> {
     map(
         separated_pair(ws(operand), ws(tag("|=")), ws(operand)),
         |(l, r)| Instruction::OrAssign(l, r),
     )(input)
 }
 
-fn call(input: &str) -> IResult<Instruction> {
+fn add_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("+=")), ws(operand)),
+        |(l, r)| Instruction::AddAssign(l, r),
+    )(input)
+}
+
+fn add_carry_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("+c=")), ws(operand)),
+        |(l, r)| Instruction::AddCarryAssign(l, r),
+    )(input)
+}
+
+fn sub_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("-=")), ws(operand)),
+        |(l, r)| Instruction::SubAssign(l, r),
+    )(input)
+}
+
+fn sub_carry_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("-c=")), ws(operand)),
+        |(l, r)| Instruction::SubCarryAssign(l, r),
+    )(input)
+}
+
+fn xor_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("^=")), ws(operand)),
+        |(l, r)| Instruction::XorAssign(l, r),
+    )(input)
+}
+
+fn shift_left_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("<<=")), ws(operand)),
+        |(l, r)| Instruction::ShiftLeftAssign(l, r),
+    )(input)
+}
+
+fn shift_right_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag(">>=")), ws(operand)),
+        |(l, r)| Instruction::ShiftRightAssign(l, r),
+    )(input)
+}
+
+fn rotate_left_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag("<<c=")), ws(operand)),
+        |(l, r)| Instruction::RotateLeftAssign(l, r),
+    )(input)
+}
+
+fn rotate_right_assign(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        separated_pair(ws(operand), ws(tag(">>c=")), ws(operand)),
+        |(l, r)| Instruction::RotateRightAssign(l, r),
+    )(input)
+}
+
+fn increment(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        terminated(ws(operand), ws(tag("++"))),
+        Instruction::Increment,
+    )(input)
+}
+
+fn decrement(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        terminated(ws(operand), ws(tag("--"))),
+        Instruction::Decrement,
+    )(input)
+}
+
+fn call(input: &str) -> IResult<'_, Instruction<'_>> {
     map(terminated(ws(identifier), ws(tag("()"))), Instruction::Call)(input)
 }
 
-fn push(input: &str) -> IResult<Instruction> {
+fn push(input: &str) -> IResult<'_, Instruction<'_>> {
     map(preceded(ws(tag("PUSH")), ws(operand)), Instruction::Push)(input)
 }
 
-fn pop(input: &str) -> IResult<Instruction> {
+fn pop(input: &str) -> IResult<'_, Instruction<'_>> {
     map(preceded(ws(tag("POP")), ws(operand)), Instruction::Pop)(input)
 }
 
-fn conditional(input: &str) -> IResult<Conditional> {
+// A and B are the two halves of the accumulator, so they can be named
+// either way round
+fn swap(input: &str) -> IResult<'_, Instruction<'_>> {
+    value(
+        Instruction::Swap,
+        preceded(
+            ws(tag("SWAP")),
+            alt((
+                separated_pair(ws(tag("A")), ws(tag(",")), ws(tag("B"))),
+                separated_pair(ws(tag("B")), ws(tag(",")), ws(tag("A"))),
+            )),
+        ),
+    )(input)
+}
+
+// For instructions the language has no way to express. Any modes the
+// bytes change have to be declared afterwards.
+fn bytes(input: &str) -> IResult<'_, Instruction<'_>> {
+    map(
+        preceded(
+            ws(tag("BYTES")),
+            pair(
+                delimited(
+                    ws(tag("(")),
+                    separated_list(ws(tag(",")), ws(number)),
+                    ws(tag(")")),
+                ),
+                attributes,
+            ),
+        ),
+        |(bytes, attributes)| Instruction::Bytes(bytes, attributes),
+    )(input)
+}
+
+fn conditional(input: &str) -> IResult<'_, Conditional<'_>> {
     context(
         "conditional",
         delimited(
             ws(tag("(")),
-            alt((equality, bit_test, not_bit_test)),
+            alt((flag, equality, comparison, bit_test, not_bit_test)),
             ws(tag(")")),
         ),
     )(input)
 }
 
-fn bit_test(input: &str) -> IResult<Conditional> {
+// Branch on flags left over from the previous instruction
+fn flag(input: &str) -> IResult<'_, Conditional<'_>> {
+    context(
+        "flag",
+        alt((
+            map(preceded(ws(tag("!")), ws(flag_name)), Conditional::NotFlag),
+            map(ws(flag_name), Conditional::Flag),
+        )),
+    )(input)
+}
+
+fn flag_name(input: &str) -> IResult<'_, Flag> {
+    alt((
+        value(Flag::Carry, tag("CARRY")),
+        value(Flag::Negative, tag("NEGATIVE")),
+        value(Flag::Overflow, tag("OVERFLOW")),
+        value(Flag::Zero, tag("ZERO")),
+    ))(input)
+}
+
+fn bit_test(input: &str) -> IResult<'_, Conditional<'_>> {
     context(
         "bit_test",
         map(
@@ -194,7 +512,7 @@ fn bit_test(input: &str) -> IResult<Conditional> {
     )(input)
 }
 
-fn not_bit_test(input: &str) -> IResult<Conditional> {
+fn not_bit_test(input: &str) -> IResult<'_, Conditional<'_>> {
     context(
         "not_bit_test",
         map(
@@ -204,7 +522,7 @@ fn not_bit_test(input: &str) -> IResult<Conditional> {
     )(input)
 }
 
-fn equality(input: &str) -> IResult<Conditional> {
+fn equality(input: &str) -> IResult<'_, Conditional<'_>> {
     context(
         "equality",
         map(
@@ -214,19 +532,79 @@ fn equality(input: &str) -> IResult<Conditional> {
     )(input)
 }
 
-fn operand(input: &str) -> IResult<Operand> {
+fn comparison(input: &str) -> IResult<'_, Conditional<'_>> {
+    context(
+        "comparison",
+        map(
+            tuple((ws(operand), ws(comparison_operator), ws(operand))),
+            |(lhs, op, rhs)| op(lhs, rhs),
+        ),
+    )(input)
+}
+
+type Comparison<'a> = fn(Operand<'a>, Operand<'a>) -> Conditional<'a>;
+
+// Signed comparisons have an `s` on the end, which mustn't run into
+// a variable name
+fn comparison_operator<'a>(input: &'a str) -> IResult<'a, Comparison<'a>> {
+    alt((
+        value(Conditional::Inequality as Comparison, tag("!=")),
+        value(Conditional::SignedLessEqual as Comparison, signed("<=s")),
+        value(Conditional::LessEqual as Comparison, tag("<=")),
+        value(Conditional::SignedLess as Comparison, signed("<s")),
+        value(Conditional::Less as Comparison, tag("<")),
+        value(Conditional::SignedGreaterEqual as Comparison, signed(">=s")),
+        value(Conditional::GreaterEqual as Comparison, tag(">=")),
+        value(Conditional::SignedGreater as Comparison, signed(">s")),
+        value(Conditional::Greater as Comparison, tag(">")),
+    ))(input)
+}
+
+fn signed<'a>(op: &'static str) -> impl Fn(&'a str) -> IResult<'a, &'a str> {
+    terminated(tag(op), not(identifier))
+}
+
+fn operand(input: &str) -> IResult<'_, Operand<'_>> {
     context(
         "operand",
-        alt((
-            map(number, Operand::Immediate),
-            map(preceded(tag("*"), number), Operand::Absolute),
-            map(register, Operand::Register),
-            map(identifier, Operand::Variable),
-        )),
+        map(
+            pair(base_operand, opt(delimited(tag("["), index, tag("]")))),
+            |(base, index)| match index {
+                Some(index) => Operand::Indexed(Box::new(base), index),
+                None => base,
+            },
+        ),
     )(input)
 }
 
-fn register(input: &str) -> IResult<Register> {
+fn base_operand(input: &str) -> IResult<'_, Operand<'_>> {
+    alt((
+        map(number, Operand::Immediate),
+        map(preceded(tag("*"), number), Operand::Absolute),
+        map(
+            preceded(tag("D"), delimited(tag("["), number, tag("]"))),
+            Operand::Direct,
+        ),
+        map(
+            preceded(tag("S"), delimited(tag("["), number, tag("]"))),
+            Operand::Stack,
+        ),
+        map(delimited(tag("("), operand, tag(")")), |inner| {
+            Operand::Indirect(Box::new(inner))
+        }),
+        map(delimited(tag("["), operand, tag("]")), |inner| {
+            Operand::IndirectLong(Box::new(inner))
+        }),
+        map(register, Operand::Register),
+        map(identifier, Operand::Variable),
+    ))(input)
+}
+
+fn index(input: &str) -> IResult<'_, Register> {
+    alt((value(Register::X, tag("X")), value(Register::Y, tag("Y"))))(input)
+}
+
+fn register(input: &str) -> IResult<'_, Register> {
     context(
         "register",
         alt((
@@ -236,32 +614,37 @@ fn register(input: &str) -> IResult<Register> {
             value(Register::X, tag("X")),
             value(Register::Y, tag("Y")),
             value(Register::S, tag("S")),
-            value(Register::D, tag("D")),
+            // DB has to be tried first, or D would match its start
             value(Register::DB, tag("DB")),
+            value(Register::D, tag("D")),
             value(Register::PB, tag("PB")),
         )),
     )(input)
 }
 
-fn identifier(input: &str) -> IResult<&str> {
+fn identifier(input: &str) -> IResult<'_, &str> {
     is_a("abcdefghijklmnopqrstuvwxyz_")(input)
 }
 
-fn number(input: &str) -> IResult<u32> {
+fn string(input: &str) -> IResult<'_, &str> {
+    delimited(tag("\""), take_until("\""), tag("\""))(input)
+}
+
+fn number(input: &str) -> IResult<'_, u32> {
     context("number", alt((octal, hexadecimal, decimal)))(input)
 }
 
-fn decimal(input: &str) -> IResult<u32> {
-    map(digit1, |digits| u32::from_str_radix(digits, 10).unwrap())(input)
+fn decimal(input: &str) -> IResult<'_, u32> {
+    map(digit1, |digits: &str| digits.parse().unwrap())(input)
 }
 
-fn hexadecimal(input: &str) -> IResult<u32> {
+fn hexadecimal(input: &str) -> IResult<'_, u32> {
     map(preceded(tag_no_case("0x"), hex_digit1), |digits| {
         u32::from_str_radix(digits, 16).unwrap()
     })(input)
 }
 
-fn octal(input: &str) -> IResult<u32> {
+fn octal(input: &str) -> IResult<'_, u32> {
     map(preceded(tag_no_case("0o"), oct_digit1), |digits| {
         u32::from_str_radix(digits, 8).unwrap()
     })(input)
@@ -273,7 +656,7 @@ fn ws<'a, T, F: Fn(&'a str) -> IResult<'a, T>>(
     preceded(comment, combinator)
 }
 
-fn comment(input: &str) -> IResult<Vec<&str>> {
+fn comment(input: &str) -> IResult<'_, Vec<&str>> {
     context(
         "comment",
         preceded(
@@ -303,6 +686,107 @@ mod tests {
         );
     }
 
+    #[test]
+    fn header() {
+        let (rest, items) = complete(all_consuming(super::header))(
+            "{ title := \"SNAZZY TEST\"; region := 0x02; sram_size := 8; }",
+        )
+        .unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(items[1].span, Span("region := 0x02;"));
+        assert_eq!(
+            items.into_iter().map(|item| item.field).collect::<Vec<_>>(),
+            vec![
+                HeaderField::Title("SNAZZY TEST"),
+                HeaderField::Region(2),
+                HeaderField::SramSize(8),
+            ]
+        );
+    }
+
+    #[test]
+    fn data() {
+        let result =
+            complete(all_consuming(super::data))("WORD palette [BANK(1)] { 0x3C00, 0x01E0, }");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Data {
+                    attributes: vec![Attribute::Bank(1)],
+                    contents: DataContents::Table(Width::Word, vec![0x3C00, 0x01E0]),
+                    name: "palette",
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn incbin() {
+        let result = complete(all_consuming(super::incbin))("tiles \"tiles.bin\";");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Data {
+                    attributes: vec![],
+                    contents: DataContents::Binary("tiles.bin"),
+                    name: "tiles",
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn operand() {
+        let result = complete(all_consuming(super::operand))("(D[0x10])[Y]");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Operand::Indexed(
+                    Box::new(Operand::Indirect(Box::new(Operand::Direct(0x10)))),
+                    Register::Y
+                )
+            ))
+        );
+    }
+
+    #[test]
+    fn register() {
+        let register = |input| complete(all_consuming(super::register))(input);
+        assert_eq!(register("D"), Ok(("", Register::D)));
+        assert_eq!(register("DB"), Ok(("", Register::DB)));
+        assert_eq!(register("PB"), Ok(("", Register::PB)));
+    }
+
+    #[test]
+    fn swap() {
+        let swap = |input| complete(all_consuming(super::swap))(input);
+        assert_eq!(swap("SWAP B, A"), Ok(("", Instruction::Swap)));
+        assert!(swap("SWAP A, X").is_err());
+    }
+
+    #[test]
+    fn comparison() {
+        let result = complete(all_consuming(super::conditional))("(X <s size)");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Conditional::SignedLess(Operand::Register(Register::X), Operand::Variable("size"))
+            ))
+        );
+        let result = complete(all_consuming(super::conditional))("(X <size)");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Conditional::Less(Operand::Register(Register::X), Operand::Variable("size"))
+            ))
+        );
+    }
+
     #[test]
     fn empty_function() {
         let result = complete(all_consuming(super::function))("main [] {}");
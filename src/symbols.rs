@@ -0,0 +1,79 @@
+use super::codegen::{Rom, Symbol};
+use std::fmt::Write;
+
+/// The WLA DX symbol file format, which no$sns and bsnes-plus read too
+pub fn sym(rom: &Rom) -> String {
+    let mut output = "; wla symbolic information file\n\n[labels]\n".to_string();
+    for symbol in &rom.symbols {
+        writeln!(
+            output,
+            "{:02x}:{:04x} {}",
+            symbol.addr >> 16,
+            symbol.addr & 0xFFFF,
+            symbol.name
+        )
+        .unwrap();
+    }
+    output
+}
+
+/// Mesen's label file format. Each label is against a type of memory,
+/// rather than an address, so any that aren't somewhere Mesen knows
+/// about are left out.
+pub fn mlb(rom: &Rom) -> String {
+    let mut output = String::new();
+    for symbol in &rom.symbols {
+        if let Some((memory, addr)) = mesen_memory(symbol) {
+            writeln!(output, "{}:{:X}:{}", memory, addr, symbol.name).unwrap();
+        }
+    }
+    output
+}
+
+fn mesen_memory(symbol: &Symbol) -> Option<(&'static str, usize)> {
+    if let Some(offset) = symbol.offset {
+        return Some(("SnesPrgRom", offset));
+    }
+    let bank = symbol.addr >> 16;
+    let addr = symbol.addr & 0xFFFF;
+    // Banks 0x00-0x3F and 0x80-0xBF all see the first 8KB of WRAM,
+    // and the registers above it
+    let system = bank & 0x7F < 0x40;
+    match bank {
+        0x7E | 0x7F => Some(("SnesWorkRam", symbol.addr - 0x7E0000)),
+        _ if system && addr < 0x2000 => Some(("SnesWorkRam", addr)),
+        _ if system && addr < 0x6000 => Some(("SnesRegister", addr)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory() {
+        let symbol = |addr, offset| Symbol {
+            name: "x",
+            addr,
+            offset,
+        };
+        assert_eq!(
+            mesen_memory(&symbol(0x8000, Some(0))),
+            Some(("SnesPrgRom", 0))
+        );
+        assert_eq!(
+            mesen_memory(&symbol(0x7F0010, None)),
+            Some(("SnesWorkRam", 0x10010))
+        );
+        assert_eq!(
+            mesen_memory(&symbol(0x800010, None)),
+            Some(("SnesWorkRam", 0x10))
+        );
+        assert_eq!(
+            mesen_memory(&symbol(0x4210, None)),
+            Some(("SnesRegister", 0x4210))
+        );
+        assert_eq!(mesen_memory(&symbol(0x708000, None)), None);
+    }
+}
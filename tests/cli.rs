@@ -0,0 +1,110 @@
+use std::path::PathBuf;
+use std::process::Command;
+
+fn snazzy() -> Command {
+    Command::new(env!("CARGO_BIN_EXE_snazzy"))
+}
+
+fn temp_file(name: &str, contents: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("snazzy-cli-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join(name);
+    std::fs::write(&path, contents).unwrap();
+    path
+}
+
+#[test]
+fn output_and_defines() {
+    let input = temp_file("defines.snz", "FUN main { A := status; }");
+    let output = input.with_extension("sfc");
+    let status = snazzy()
+        .arg(&input)
+        .args(["-o", output.to_str().unwrap(), "-D", "status=0x4210"])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let image = std::fs::read(&output).unwrap();
+    assert_eq!(&[0xAD, 0x10, 0x42, 0x60], &image[0..4]);
+}
+
+#[test]
+fn errors() {
+    let input = temp_file("errors.snz", "FUN main {\n  X += 1;\n}\n");
+    let result = snazzy().arg(&input).output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    let stderr = String::from_utf8(result.stderr).unwrap();
+    assert!(stderr.contains("errors.snz:2:3"));
+
+    let result = snazzy().arg(&input).arg("-q").output().unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(result.stderr.is_empty());
+
+    let result = snazzy().output().unwrap();
+    assert_eq!(result.status.code(), Some(2));
+    let result = snazzy()
+        .args(["--format", "elf", "x.snz"])
+        .output()
+        .unwrap();
+    assert_eq!(result.status.code(), Some(2));
+}
+
+#[test]
+fn symbols() {
+    let input = temp_file(
+        "symbols.snz",
+        "VAR counter := 0x7E0010;\nFUN main { A := counter; }\n",
+    );
+    let sym = input.with_extension("sym");
+    let mlb = input.with_extension("mlb");
+    for path in [&sym, &mlb] {
+        let status = snazzy()
+            .arg(&input)
+            .args(["--symbols", path.to_str().unwrap()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+    let sym = std::fs::read_to_string(&sym).unwrap();
+    assert!(sym.contains("[labels]\n00:8000 main\n7e:0010 counter\n"));
+    let mlb = std::fs::read_to_string(&mlb).unwrap();
+    assert_eq!(mlb, "SnesPrgRom:0:main\nSnesWorkRam:10:counter\n");
+}
+
+#[test]
+fn disasm() {
+    let input = temp_file(
+        "disasm.snz",
+        "FUN reset [EMU, INTR] { [NAT, WIDEM] { C := 0x1234; } }",
+    );
+    let rom = input.with_extension("sfc");
+    let status = snazzy()
+        .arg(&input)
+        .args(["-o", rom.to_str().unwrap()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let result = snazzy().arg("disasm").arg(&rom).output().unwrap();
+    assert!(result.status.success());
+    assert_eq!(
+        String::from_utf8(result.stdout).unwrap(),
+        "00:8000  18          CLC\n\
+         00:8001  FB          XCE\n\
+         00:8002  C2 20       REP #$20\n\
+         00:8004  A9 34 12    LDA #$1234\n\
+         00:8007  38          SEC\n\
+         00:8008  FB          XCE\n\
+         00:8009  E2 30       SEP #$30\n\
+         00:800B  40          RTI\n"
+    );
+
+    let result = snazzy()
+        .arg("disasm")
+        .arg(&rom)
+        .args(["--start", "0x8000", "--length", "0xFFFFFFFF"])
+        .output()
+        .unwrap();
+    assert_eq!(result.status.code(), Some(1));
+    assert!(String::from_utf8(result.stderr)
+        .unwrap()
+        .contains("past the end of the address space"));
+}
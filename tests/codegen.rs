@@ -1,4 +1,5 @@
-use snazzy::{codegen, parser};
+use snazzy::ast::{Attribute, Operand, Register};
+use snazzy::{assembly, codegen, diagnostic, disasm, listing, parser};
 
 #[test]
 fn empty() {
@@ -19,29 +20,15 @@ fn simple() {
 
 #[test]
 fn snes() {
-    let expected_code = vec![
-        64, 64, 64, 64, 64, 64, 120, 24, 251, 194, 32, 169, 255, 1, 27, 169, 0, 0, 91, 226, 48,
-        169, 143, 141, 0, 33, 156, 1, 33, 156, 2, 33, 156, 3, 33, 156, 5, 33, 156, 6, 33, 156, 7,
-        33, 156, 8, 33, 156, 9, 33, 156, 10, 33, 156, 11, 33, 156, 12, 33, 156, 13, 33, 156, 13,
-        33, 169, 255, 141, 14, 33, 141, 16, 33, 141, 18, 33, 141, 20, 33, 169, 7, 141, 14, 33, 141,
-        16, 33, 141, 18, 33, 141, 20, 33, 156, 15, 33, 156, 15, 33, 156, 17, 33, 156, 17, 33, 156,
-        19, 33, 156, 19, 33, 169, 128, 141, 21, 33, 156, 22, 33, 156, 23, 33, 156, 26, 33, 156, 27,
-        33, 169, 1, 141, 27, 33, 156, 28, 33, 156, 28, 33, 156, 29, 33, 156, 29, 33, 156, 30, 33,
-        141, 30, 33, 156, 31, 33, 156, 31, 33, 156, 32, 33, 156, 32, 33, 156, 33, 33, 156, 35, 33,
-        156, 36, 33, 156, 37, 33, 156, 38, 33, 156, 39, 33, 156, 40, 33, 156, 41, 33, 156, 42, 33,
-        156, 43, 33, 141, 44, 33, 156, 45, 33, 156, 46, 33, 156, 47, 33, 169, 48, 141, 48, 33, 156,
-        49, 33, 169, 224, 141, 50, 33, 156, 51, 33, 169, 255, 156, 0, 66, 141, 1, 66, 156, 2, 66,
-        156, 3, 66, 156, 4, 66, 156, 5, 66, 156, 6, 66, 156, 7, 66, 156, 8, 66, 156, 9, 66, 156,
-        10, 66, 156, 11, 66, 156, 12, 66, 156, 13, 66, 88, 32, 16, 129, 56, 251, 64, 169, 60, 156,
-        34, 33, 141, 34, 33, 169, 2, 141, 0, 0, 169, 129, 141, 0, 66, 173, 0, 0, 137, 1, 240, 249,
-        173, 0, 0, 9, 2, 41, 254, 141, 0, 0, 128, 237, 96, 194, 48, 72, 218, 90, 226, 48, 173, 0,
-        0, 137, 2, 240, 20, 169, 143, 141, 0, 33, 169, 15, 141, 0, 33, 173, 0, 0, 9, 1, 41, 253,
-        141, 0, 0, 194, 48, 122, 250, 104, 226, 48, 64,
-    ];
+    // One instruction per line, with each function commented
+    let expected_code: Vec<_> = include_str!("input/snes.asm")
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with(';'))
+        .collect();
     let expected_header = vec![
         90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 90, 32, 0,
-        5, 0, 1, 51, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 128, 1, 128, 0, 0, 54, 129, 0, 0, 2, 128, 0, 0,
-        0, 0, 3, 128, 0, 0, 0, 0, 4, 128, 6, 128, 5, 128,
+        5, 0, 1, 51, 0, 57, 130, 198, 125, 0, 0, 0, 0, 0, 128, 1, 128, 0, 0, 54, 129, 0, 0, 2, 128,
+        0, 0, 0, 0, 3, 128, 0, 0, 0, 0, 4, 128, 6, 128, 5, 128,
     ];
     let ast = parser::program(include_str!("input/snes.snz"));
     if let Err(e) = &ast {
@@ -60,20 +47,982 @@ fn snes() {
         }
     }
     assert!(ast.is_ok());
-    use std::io::Write;
     let bytes = codegen::assemble(ast.as_ref().unwrap());
     if let Err(e) = &bytes {
         println!("{:?}", e);
     }
     assert!(bytes.is_ok());
-    std::fs::File::create("/Users/branan/test.bin")
-        .unwrap()
-        .write_all(bytes.as_ref().unwrap())
-        .unwrap();
     let bytes = bytes.unwrap();
-    assert_eq!(expected_code, &bytes[0..expected_code.len()]);
+    let reset = disasm::State {
+        emulation: true,
+        wide_math: false,
+        wide_index: false,
+    };
+    let lines = disasm::disassemble(&bytes[0..0x7FC0], 0x8000, reset);
+    let code: Vec<_> = lines[0..expected_code.len()]
+        .iter()
+        .map(|line| line.instruction.unwrap().to_string())
+        .collect();
+    assert_eq!(expected_code, code);
     assert_eq!(expected_header, &bytes[0x7FC0..0x8000]);
-    assert!(bytes[expected_code.len()..0x7FC0]
+    let code_len = (lines[expected_code.len()].addr - 0x8000) as usize;
+    assert!(bytes[code_len..0x7FC0].iter().all(|b| *b == 0x00));
+}
+
+#[test]
+fn snes_checksum() {
+    let ast = parser::program(include_str!("input/snes.snz"));
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    // complement, then checksum
+    assert_eq!(&[0x39, 0x82, 0xC6, 0x7D], &bytes[0x7FDC..0x7FE0]);
+}
+
+#[test]
+fn colors_checksum() {
+    let ast = parser::program(include_str!("../examples/colors.snz"));
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    assert_eq!(&[0x62, 0x6A, 0x9D, 0x95], &bytes[0x7FDC..0x7FE0]);
+    let sum = bytes
         .iter()
-        .all(|b| *b == 0x00));
+        .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    assert_eq!(sum, u16::from_le_bytes([bytes[0x7FDE], bytes[0x7FDF]]));
+}
+
+#[test]
+fn header() {
+    let ast = parser::program(
+        r#"
+        HEADER {
+          title := "SNAZZY";
+          region := 0x02;
+          rom_size := 64;
+          sram_size := 8;
+          version := 3;
+          developer := 0x01;
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    assert_eq!(bytes.len(), 0x10000);
+    assert_eq!(b"SNAZZY               ", &bytes[0x7FC0..0x7FD5]);
+    assert_eq!(
+        &[0x20, 0x02, 0x06, 0x03, 0x02, 0x01, 0x03],
+        &bytes[0x7FD5..0x7FDC]
+    );
+
+    // A size that isn't a power of two is rounded up in the header,
+    // and its last bank counts twice in the checksum
+    let ast = parser::program("HEADER { rom_size := 96; } FUN main [BANK(2)] {}").unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(bytes.len(), 0x18000);
+    assert_eq!(0x07, bytes[0x7FD7]);
+    let sum = bytes
+        .iter()
+        .chain(&bytes[0x10000..])
+        .fold(0u16, |sum, b| sum.wrapping_add(*b as u16));
+    assert_eq!(sum, u16::from_le_bytes([bytes[0x7FDE], bytes[0x7FDF]]));
+}
+
+#[test]
+fn bad_header() {
+    let ast = parser::program(r#"HEADER { title := "THIS TITLE IS FAR TOO LONG"; }"#).unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::TitleTooLong("THIS TITLE IS FAR TOO LONG"))
+    );
+    let ast = parser::program(r#"HEADER { title := "snazzy~"; region := 0x20; }"#).unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidHeaderValue("region", 0x20))
+    );
+    let ast = parser::program(r#"HEADER { rom_size := 48; }"#).unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidHeaderValue("rom_size", 48))
+    );
+    let ast = parser::program("HEADER {} HEADER {}").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::DuplicateHeader)
+    );
+    let ast = parser::program("HEADER { region := 1; version := 2; region := 2; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::DuplicateHeaderField("region"))
+    );
+
+    // Errors point at the field, HEADER or function they're about,
+    // even when they're only found while laying out the image
+    let sources = [
+        (
+            "HEADER { version := 1; region := 0x20; }",
+            "region := 0x20;",
+        ),
+        ("HEADER { version := 1; version := 2; }", "version := 2;"),
+        ("HEADER {} HEADER {}", "HEADER"),
+        (
+            "HEADER { rom_size := 32; } FUN main [BANK(1)] {}",
+            "rom_size := 32;",
+        ),
+        ("FUN nmi {}", "nmi"),
+    ];
+    for (source, span) in sources {
+        let ast = parser::program(source).unwrap();
+        let errors = codegen::assemble(&ast).unwrap_err();
+        assert_eq!(errors[0].span.map(|span| span.0), Some(span), "{}", source);
+    }
+}
+
+#[test]
+fn banks() {
+    let ast = parser::program(
+        r#"
+        VAR reg := 0x1000;
+        FUN far [BANK(2)] {
+          A := 0x30;
+          reg := A;
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    // Three banks, rounded up to 128KB in the header
+    assert_eq!(bytes.len(), 0x18000);
+    assert_eq!(bytes[0x7FD7], 0x07);
+    assert_eq!(&[169, 48, 141, 0, 16, 96], &bytes[0x10000..0x10006]);
+    assert!(bytes[0x8000..0x10000].iter().all(|b| *b == 0x00));
+
+    let ast = parser::program("FUN far [BANK(0x40)] {}").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidBank(0x40, "far"))
+    );
+    let ast = parser::program("FUN nmi [BANK(1), INTR] {}").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidInterruptBank("nmi"))
+    );
+}
+
+#[test]
+fn extern_call() {
+    let ast = parser::program(
+        r#"
+        FUN main {
+          far();
+          near();
+        }
+        FUN far [EXTERN, BANK(1)] {}
+        FUN near {
+          far();
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    assert_eq!(
+        &[0x22, 0x00, 0x80, 0x01, 0x20, 0x08, 0x80, 0x60],
+        &bytes[0x0000..0x0008]
+    );
+    assert_eq!(&[0x22, 0x00, 0x80, 0x01, 0x60], &bytes[0x0008..0x000D]);
+    assert_eq!(0x6B, bytes[0x8000]);
+
+    let ast = parser::program("FUN main { far(); } FUN far [BANK(1)] {}").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::FarCall("far", "main"))
+    );
+}
+
+#[test]
+fn hirom() {
+    let ast = parser::program(
+        r#"
+        HEADER { map := HIROM; }
+        FUN reset [EMU, INTR] {}
+        FUN far [EXTERN, BANK(1)] {}
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    assert_eq!(bytes.len(), 0x20000);
+    assert_eq!(0x40, bytes[0x8000]);
+    assert_eq!(0x6B, bytes[0x10000]);
+    assert_eq!(&[0x21, 0x00, 0x07], &bytes[0xFFD5..0xFFD8]);
+    assert_eq!(&[0x00, 0x80], &bytes[0xFFFC..0xFFFE]);
+}
+
+#[test]
+fn exhirom() {
+    let ast = parser::program(
+        r#"
+        HEADER { map := EXHIROM; }
+        FUN reset [EMU, INTR] {}
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    assert_eq!(bytes.len(), 0x410000);
+    assert_eq!(0x40, bytes[0x408000]);
+    assert_eq!(&[0x25, 0x00, 0x0D], &bytes[0x40FFD5..0x40FFD8]);
+    assert_eq!(&[0x00, 0x80], &bytes[0x40FFFC..0x40FFFE]);
+
+    let ast = parser::program("HEADER { map := EXHIROM; } FUN far [BANK(0x3E)] {}").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidBank(0x3E, "far"))
+    );
+}
+
+#[test]
+fn data() {
+    let ast = parser::program(
+        r#"
+        DATA bytes { 1, 2, 3 }
+        DATA WORD words { 0x1234, 0x5678 }
+        DATA LONG longs [BANK(1)] { 0x123456 }
+        INCBIN tiles "data.bin";
+        FUN main {
+          A := words;
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes =
+        codegen::assemble_from(ast.as_ref().unwrap(), std::path::Path::new("tests/input")).unwrap();
+    assert_eq!(
+        &[1, 2, 3, 0x34, 0x12, 0x78, 0x56, 1, 2, 3, 4, 0xAD, 0x03, 0x80, 0x60],
+        &bytes[0x0000..0x000F]
+    );
+    assert_eq!(&[0x56, 0x34, 0x12], &bytes[0x8000..0x8003]);
+
+    // Data in another bank has a 24-bit address, which only A and C
+    // can reach
+    let source = "DATA LONG longs [BANK(1)] { 0x123456 } FUN main { A := longs[X]; }";
+    let ast = parser::program(source).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0xBF, 0x00, 0x80, 0x01, 0x60], &bytes[0..5]);
+    let ast = parser::program("DATA longs [BANK(1)] { 1 } FUN main { X := longs; }").unwrap();
+    let errors = codegen::assemble(&ast).unwrap_err();
+    assert!(matches!(
+        errors[0].error,
+        codegen::Error::FarOperand(Operand::Variable("longs"), "main")
+    ));
+
+    let ast = parser::program("DATA bytes { 0x100 }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidValue(0x100, "bytes"))
+    );
+    let ast = parser::program(r#"INCBIN missing "missing.bin";"#).unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::IncludeFailed(
+            "missing.bin",
+            std::io::ErrorKind::NotFound
+        ))
+    );
+}
+
+#[test]
+fn addressing_modes() {
+    let ast = parser::program(
+        r#"
+        VAR table := 0x1000;
+        VAR far := 0x7E2000;
+        FUN main {
+          A := table[X];
+          A := table[Y];
+          A := far;
+          A := far[X];
+          A := D[0x10];
+          A := D[0x10][X];
+          A := (D[0x10]);
+          A := (D[0x10])[Y];
+          A := [D[0x10]];
+          A := [D[0x10]][Y];
+          A := S[3];
+          A := (S[3])[Y];
+          far := A;
+          table[X] := A;
+          X := table[Y];
+          Y := D[0x20][X];
+          D[0x20][Y] := X;
+          table[X] := 0;
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0xBD, 0x00, 0x10, 0xB9, 0x00, 0x10, 0xAF, 0x00, 0x20, 0x7E, 0xBF, 0x00, 0x20, 0x7E, 0xA5,
+        0x10, 0xB5, 0x10, 0xB2, 0x10, 0xB1, 0x10, 0xA7, 0x10, 0xB7, 0x10, 0xA3, 0x03, 0xB3, 0x03,
+        0x8F, 0x00, 0x20, 0x7E, 0x9D, 0x00, 0x10, 0xBE, 0x00, 0x10, 0xB4, 0x20, 0x96, 0x20, 0x9E,
+        0x00, 0x10, 0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("VAR far := 0x7E2000; FUN main { X := far; }").unwrap();
+    assert!(matches!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::FarOperand(_, "main"))
+    ));
+    let ast = parser::program("VAR far := 0x7E2000; FUN main { A := far[Y]; }").unwrap();
+    assert!(matches!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::FarOperand(_, "main"))
+    ));
+    let ast = parser::program("FUN main { A := D[0x100]; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidAddress(0x100, "main"))
+    );
+}
+
+#[test]
+fn math() {
+    let ast = parser::program(
+        r#"
+        VAR lo := 0x0010;
+        VAR hi := 0x0011;
+        FUN main {
+          A += 1;
+          A -= lo;
+          A := lo;
+          A +c= 0x20;
+          A -c= D[0x10][X];
+          X++;
+          Y--;
+          A++;
+          lo++;
+          hi--;
+          [WIDEM] {
+            C += 0x1234;
+          }
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0x18, 0x69, 0x01, 0x38, 0xED, 0x10, 0x00, 0xAD, 0x10, 0x00, 0x69, 0x20, 0xF5, 0x10, 0xE8,
+        0x88, 0x1A, 0xEE, 0x10, 0x00, 0xCE, 0x11, 0x00, 0xC2, 0x20, 0x18, 0x69, 0x34, 0x12, 0xE2,
+        0x30, 0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("FUN main { C += 1; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidRegister(
+            snazzy::ast::Register::C,
+            snazzy::ast::Attribute::NarrowMath,
+            "main"
+        ))
+    );
+    let ast = parser::program("FUN main { X += 1; }").unwrap();
+    assert!(matches!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::BadAddAssignment(_, _, "main"))
+    ));
+}
+
+#[test]
+fn bits() {
+    let ast = parser::program(
+        r#"
+        VAR oam := 0x0300;
+        FUN main {
+          A ^= 0xFF;
+          A ^= oam[X];
+          A <<= 3;
+          A >>= 1;
+          A <<c= 1;
+          A >>c= 1;
+          oam <<c= 2;
+          D[0x10][X] >>c= 1;
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0x49, 0xFF, 0x5D, 0x00, 0x03, 0x0A, 0x0A, 0x0A, 0x4A, 0x2A, 0x6A, 0x2E, 0x00, 0x03, 0x2E,
+        0x00, 0x03, 0x76, 0x10, 0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("FUN main { A <<= 9; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidValue(9, "main"))
+    );
+    let ast = parser::program("FUN main { X >>= 1; }").unwrap();
+    assert!(matches!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::BadShift(_, _, "main"))
+    ));
+}
+
+#[test]
+fn break_continue() {
+    let ast = parser::program(
+        r#"
+        VAR status := 0x0000;
+        FUN main {
+          DO {
+            A := status;
+            IF (A == 1) {
+              BREAK;
+            }
+            [WIDEM] {
+              CONTINUE;
+            }
+          }
+          DO {
+            CONTINUE;
+          } WHILE (A && 1)
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        // DO {
+        0xAD, 0x00, 0x00, // A := status
+        0xC9, 0x01, 0xD0, 0x02, // IF (A == 1)
+        0x80, 0x0A, // BREAK
+        0xC2, 0x20, // [WIDEM]
+        0xE2, 0x30, 0x80, 0xF1, // CONTINUE, back in narrow mode
+        0xE2, 0x30, // end of [WIDEM]
+        0x80, 0xED, // }
+        // DO {
+        0x80, 0x00, // CONTINUE
+        // } WHILE (A && 1)
+        0x89, 0x01, 0xD0, 0xFA, 0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("FUN main { BREAK; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::BreakOutsideLoop("main"))
+    );
+    let ast = parser::program("FUN main { CONTINUE; }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::ContinueOutsideLoop("main"))
+    );
+}
+
+#[test]
+fn mode_switches() {
+    // Widening only the index registers while the accumulator is
+    // already wide still needs a REP on the way in, and a SEP out
+    let ast = parser::program("FUN main [WIDEM] { [WIDEX] { X := 0x1234; } }");
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![0xC2, 0x30, 0xA2, 0x34, 0x12, 0xE2, 0x10, 0x60];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+}
+
+#[test]
+fn if_else() {
+    let ast = parser::program(
+        r#"
+        FUN main {
+          IF (A == 1) {
+            X := 1;
+          } ELSE IF (A == 2) {
+            X := 2;
+          } ELSE [WIDEX] {
+            X := 0x0300;
+          }
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0xC9, 0x01, 0xD0, 0x04, // IF (A == 1)
+        0xA2, 0x01, // X := 1
+        0x80, 0x0F, // skip ELSE
+        0xC9, 0x02, 0xD0, 0x04, // IF (A == 2)
+        0xA2, 0x02, // X := 2
+        0x80, 0x07, // skip ELSE
+        0xC2, 0x10, 0xA2, 0x00, 0x03, 0xE2, 0x30, // X := 0x0300
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    // An ELSE too long for a BRA is jumped over instead
+    let long_else = format!(
+        "FUN main {{ IF (A == 1) {{}} ELSE {{ {} }} }}",
+        "A := 0;".repeat(64)
+    );
+    let ast = parser::program(&long_else).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0xC9, 0x01, 0xD0, 0x03, 0x4C, 0x87, 0x80], &bytes[0..7]);
+    assert_eq!(0x60, bytes[0x87]);
+}
+
+#[test]
+fn long_branches() {
+    // An IF too long for a branch branches around a JMP instead
+    let long_if = format!("FUN main {{ IF (A == 1) {{ {} }} }}", "A := 0;".repeat(64));
+    let ast = parser::program(&long_if).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0xC9, 0x01, 0xF0, 0x03, 0x4C, 0x87, 0x80], &bytes[0..7]);
+    assert_eq!(0x60, bytes[0x87]);
+
+    // So does a conditional loop too long to branch back
+    let long_loop = format!(
+        "FUN main {{ DO {{ {} }} WHILE (A == 1) }}",
+        "A := 0;".repeat(64)
+    );
+    let ast = parser::program(&long_loop).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(
+        &[0xC9, 0x01, 0xD0, 0x03, 0x4C, 0x00, 0x80, 0x60],
+        &bytes[0x80..0x88]
+    );
+
+    // Widening a branch can push one that crosses it out of range
+    let crossing = format!(
+        "FUN main {{ DO {{ {} IF (A == 1) {{ CONTINUE; {} }} }} }}",
+        "A := 0;".repeat(61),
+        "A := 0;".repeat(64)
+    );
+    let ast = parser::program(&crossing).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(
+        &[0xC9, 0x01, 0xF0, 0x03, 0x4C, 0x04, 0x81, 0x4C, 0x00, 0x80],
+        &bytes[0x7A..0x84]
+    );
+    assert_eq!(&[0x4C, 0x00, 0x80, 0x60], &bytes[0x104..0x108]);
+
+    // A BREAK too far from the end of its loop jumps there instead
+    let long_break = format!(
+        "FUN main {{ WHILE (A == 1) {{ BREAK; {} }} }}",
+        "A := 0;".repeat(64)
+    );
+    let ast = parser::program(&long_break).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0x4C, 0x86, 0x80, 0x4C, 0x8D, 0x80], &bytes[0..6]);
+    assert_eq!(0x60, bytes[0x8D]);
+
+    // Each IF is only assembled once, however deeply they're nested
+    let nested = format!(
+        "FUN main {{ {} {} {} }}",
+        "IF (A == 1) {".repeat(24),
+        "A := 0;".repeat(64),
+        "}".repeat(24)
+    );
+    let ast = parser::program(&nested).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0xC9, 0x01, 0xF0, 0x03, 0x4C, 0x28, 0x81], &bytes[0..7]);
+    assert_eq!(0x60, bytes[0x128]);
+}
+
+#[test]
+fn comparisons() {
+    let ast = parser::program(
+        r#"
+        VAR size := 0x0010;
+        FUN main {
+          IF (A != 1) {}
+          IF (X < size) {}
+          IF (Y >= D[0x20]) {}
+          IF (A > size[X]) {}
+          IF (X <= 0x10) {}
+          IF (A <s 0x10) {}
+          IF (A >s S[1]) {}
+          [WIDEM] {
+            IF (C >=s 0x1000) {}
+          }
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0xC9, 0x01, 0xF0, 0x00, // A != 1
+        0xEC, 0x10, 0x00, 0xB0, 0x00, // X < size
+        0xC4, 0x20, 0x90, 0x00, // Y >= D[0x20]
+        0xDD, 0x10, 0x00, 0xD0, 0x01, 0x18, 0x90, 0x00, // A > size[X]
+        0xE0, 0x10, 0xD0, 0x01, 0x18, 0xB0, 0x00, // X <= 0x10
+        // A <s 0x10
+        0x48, 0x38, 0xE9, 0x10, 0x50, 0x02, 0x49, 0x80, 0x0A, 0x68, 0x90, 0x00,
+        // A >s S[1], with S[1] moved by the PHA
+        0x48, 0xA3, 0x02, 0x38, 0xE3, 0x01, 0x50, 0x02, 0x49, 0x80, 0x0A, 0x68, 0x90, 0x00, 0xC2,
+        0x20, // [WIDEM]
+        // C >=s 0x1000
+        0x48, 0x38, 0xE9, 0x00, 0x10, 0x50, 0x03, 0x49, 0x00, 0x80, 0x0A, 0x68, 0xB0, 0x00, 0xE2,
+        0x30, // end of [WIDEM]
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    // Indirect operands need the accumulator opcodes with bit 0 clear
+    let ast = parser::program(
+        "FUN main { IF (A == (D[0x10])) {} IF (A <s (D[0x10])) {} IF (A >s (D[0x10])) {} }",
+    )
+    .unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    let expected = vec![
+        0xD2, 0x10, 0xD0, 0x00, // CMP (D[0x10])
+        0x48, 0x38, 0xF2, 0x10, 0x50, 0x02, 0x49, 0x80, 0x0A, 0x68, 0x90,
+        0x00, // SBC (D[0x10])
+        0x48, 0xB2, 0x10, 0x38, 0xE3, 0x01, 0x50, 0x02, 0x49, 0x80, 0x0A, 0x68, 0x90,
+        0x00, // LDA (D[0x10])
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("FUN main { IF (X <s 1) {} }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::SignedIndexCompare(Register::X, "main"))
+    );
+    let ast = parser::program("FUN main { IF (Y >=s 1) {} }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::SignedIndexCompare(Register::Y, "main"))
+    );
+    let ast = parser::program("FUN main { IF (C && 1) {} }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::InvalidRegister(
+            snazzy::ast::Register::C,
+            snazzy::ast::Attribute::NarrowMath,
+            "main"
+        ))
+    );
+}
+
+#[test]
+fn flags() {
+    let ast = parser::program(
+        r#"
+        FUN main {
+          A >>= 1;
+          IF (CARRY) {}
+          IF (NEGATIVE) {}
+          IF (!OVERFLOW) {}
+          DO {
+            A >>= 1;
+          } WHILE (!ZERO)
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0x4A, // A >>= 1
+        0x90, 0x00, // IF (CARRY)
+        0x10, 0x00, // IF (NEGATIVE)
+        0x70, 0x00, // IF (!OVERFLOW)
+        0x4A, 0xD0, 0xFD, // DO { A >>= 1; } WHILE (!ZERO)
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+}
+
+#[test]
+fn loops() {
+    let ast = parser::program(
+        r#"
+        FUN main {
+          FOR X := 0 TO 31 {
+            A := 0;
+          }
+          FOR Y := 32 TO 1 {}
+          FOR X := 0 TO 0x01FF [WIDEX] {}
+          WHILE (X != 0) {
+            X--;
+          }
+          DO {} WHILE (ZERO)
+          WHILE (CARRY) {}
+        }
+        "#,
+    );
+    assert!(ast.is_ok());
+    let bytes = codegen::assemble(ast.as_ref().unwrap()).unwrap();
+    let expected = vec![
+        0xA2, 0x00, 0xA9, 0x00, 0xE8, 0xE0, 0x20, 0xD0, 0xF9, // FOR X := 0 TO 31
+        0xA0, 0x20, 0x88, 0xD0, 0xFD, // FOR Y := 32 TO 1
+        0xC2, 0x10, 0xA2, 0x00, 0x00, 0xE8, 0xE0, 0x00, 0x02, 0xD0, 0xFA, 0xE2,
+        0x30, // FOR X := 0 TO 0x01FF [WIDEX]
+        0x80, 0x01, 0xCA, 0xE0, 0x00, 0xD0, 0xFB, // WHILE (X != 0)
+        0xF0, 0xFE, // DO {} WHILE (ZERO)
+        0x80, 0x00, 0xB0, 0xFE, // WHILE (CARRY)
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    // Counting down stops on reaching 0, so it can't run the body for 0
+    let ast = parser::program("FUN main { FOR Y := 31 TO 0 {} }").unwrap();
+    assert_eq!(
+        codegen::assemble(&ast).map_err(|mut e| e.remove(0).error),
+        Err(codegen::Error::CountDownToZero(Register::Y, "main"))
+    );
+
+    // A WHILE after a DO is the DO's condition, so it can't have a body
+    let source = "FUN main {\n  DO { X++; }\n  WHILE (X != 5) { Y++; }\n}\n";
+    let error = parser::program(source).unwrap_err();
+    let diagnostic = diagnostic::Diagnostic::from_parse(&error);
+    assert_eq!(
+        diagnostic.message,
+        "syntax error: a DO loop's WHILE can't have a body in instruction"
+    );
+    assert_eq!(
+        diagnostic::location(source, diagnostic.span.unwrap()),
+        Some((3, 17))
+    );
+
+    // A WHILE body too long for the BRA down to the condition
+    let long_while = format!("FUN main {{ WHILE (ZERO) {{ {} }} }}", "A := 0;".repeat(64));
+    let ast = parser::program(&long_while).unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    assert_eq!(&[0x4C, 0x83, 0x80], &bytes[0..3]);
+    assert_eq!(&[0xD0, 0x03, 0x4C, 0x03, 0x80, 0x60], &bytes[0x83..0x89]);
+}
+
+#[test]
+fn error_location() {
+    let source = "FUN main {\n  IF (A == 1) {\n    X += 2;\n  }\n}\n";
+    let ast = parser::program(source).unwrap();
+    let error = codegen::assemble(&ast).unwrap_err().remove(0);
+    let span = error.span.unwrap().0;
+    assert_eq!(span, "X += 2;");
+    assert_eq!(diagnostic::location(source, span), Some((3, 5)));
+    assert_eq!(error.error.to_string(), "can't add 0x2 to X");
+
+    // Errors outside any statement point at the function
+    let source = "FUN far [BANK(0x40)] {}";
+    let ast = parser::program(source).unwrap();
+    let error = codegen::assemble(&ast).unwrap_err().remove(0);
+    assert_eq!(error.span.unwrap().0, "far");
+}
+
+#[test]
+fn all_errors() {
+    // Errors inside a long IF are reported once, in order
+    let source = format!(
+        "FUN main {{ X += 1; IF (A == 1) {{ Y <<= 1; {} }} A := 0x100; }} FUN other {{ BREAK; }}",
+        "A := 0;".repeat(64)
+    );
+    let ast = parser::program(&source).unwrap();
+    let errors = codegen::assemble(&ast).unwrap_err();
+    let spans: Vec<_> = errors.iter().map(|e| e.span.unwrap().0).collect();
+    assert_eq!(spans, vec!["X += 1;", "Y <<= 1;", "A := 0x100;", "BREAK;"]);
+    assert_eq!(errors[3].error, codegen::Error::BreakOutsideLoop("other"));
+}
+
+#[test]
+fn listing() {
+    let source = "FUN main [WIDEM] {\n  C := 0x1234;\n  [NARROWM] {\n    A := 1;\n  }\n}\n";
+    let ast = parser::program(source).unwrap();
+    let rom = codegen::assemble_rom(&ast, std::path::Path::new("")).unwrap();
+    let listing = listing::write(&rom, source);
+    let lines: Vec<_> = listing.lines().collect();
+    assert_eq!(
+        lines,
+        [
+            "main:",
+            "00:8000  A9 34 12    LDA #$1234          ; 2: C := 0x1234;",
+            "00:8003  E2 30       SEP #$30            ; 3: [NARROWM] {",
+            "00:8005  A9 01       LDA #$01            ; 4: A := 1;",
+            "00:8007  C2 20       REP #$20            ; 3: [NARROWM] {",
+            "00:8009  60          RTS                 ; 1: FUN main [WIDEM] {",
+        ]
+    );
+}
+
+#[test]
+fn ca65() {
+    let source = "
+        VAR counter := 0x0010;
+        FUN main {
+          FOR X := 0 TO 3 {
+            [WIDEM] { C := 0x1234; }
+          }
+          counter := A;
+          helper();
+        }
+        FUN helper {}
+    ";
+    let ast = parser::program(source).unwrap();
+    let rom = codegen::assemble_rom(&ast, std::path::Path::new("")).unwrap();
+    let expected = "\
+; Generated by snazzy
+.p816
+.smart -
+
+counter = $0010
+
+.export main, helper
+
+.segment \"CODE\"
+
+main:
+  .a8
+  .i8
+  ldx #$00
+@loop1:
+  rep #$20
+  .a16
+  lda #$1234
+  sep #$30
+  .a8
+  inx
+  cpx #$04
+  bne @loop1
+  sta a:counter
+  jsr helper
+  rts
+
+helper:
+  rts
+";
+    assert_eq!(assembly::ca65(&rom), expected);
+
+    // Widths only change once the CPU is out of emulation mode
+    let ast = parser::program("FUN main [EMU] { [NAT, WIDEM] { C := 0x1234; } }").unwrap();
+    let rom = codegen::assemble_rom(&ast, std::path::Path::new("")).unwrap();
+    let expected = "\
+main:
+  .a8
+  .i8
+  clc
+  xce
+  rep #$20
+  .a16
+  lda #$1234
+  sec
+  xce
+  .a8
+  sep #$30
+  rts
+";
+    assert!(assembly::ca65(&rom).ends_with(expected));
+
+    // BYTES are written out as they are, not disassembled
+    let ast = parser::program("FUN main { BYTES(0xA9); A := 1; }").unwrap();
+    let rom = codegen::assemble_rom(&ast, std::path::Path::new("")).unwrap();
+    assert!(assembly::ca65(&rom).contains("main:\n  .byte $A9\n  .a8\n  .i8\n  lda #$01\n"));
+}
+
+#[test]
+fn bytes() {
+    let ast = parser::program(
+        r#"
+        FUN main [NAT] {
+          BYTES(0xCB);
+          BYTES(0xC2, 0x20) [WIDEM];
+          C := 0x1234;
+        }
+        "#,
+    )
+    .unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    let expected = vec![
+        0xCB, // WAI
+        0xC2, 0x20, // REP #$20, declared as WIDEM
+        0xA9, 0x34, 0x12, // C := 0x1234
+        0xE2, 0x30, 0x60, // back to NARROWM for the caller
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    // The mode goes back to what the loop expects before the next pass
+    let ast = parser::program(
+        "FUN main [NAT] { FOR X := 0 TO 3 { A := 1; BYTES(0xC2, 0x20) [WIDEM]; } }",
+    )
+    .unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    let expected = vec![
+        0xA2, 0x00, 0xA9, 0x01, 0xC2, 0x20, // FOR X := 0 TO 3 { A := 1; BYTES }
+        0xE2, 0x30, // back to NARROWM
+        0xE8, 0xE0, 0x04, 0xD0, 0xF5, 0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let ast = parser::program("FUN main { BYTES(0x100); }").unwrap();
+    assert!(codegen::assemble(&ast).is_err());
+}
+
+#[test]
+fn transfers() {
+    let ast = parser::program(
+        r#"
+        FUN main [NAT] {
+          X := A;
+          A := Y;
+          Y := X;
+          SWAP A, B;
+          DB := A;
+          X := PB;
+          DB := PB;
+          [WIDEM, WIDEX] {
+            Y := C;
+            X := S;
+            C := D;
+          }
+        }
+        "#,
+    )
+    .unwrap();
+    let bytes = codegen::assemble(&ast).unwrap();
+    let expected = vec![
+        0xAA, // X := A
+        0x98, // A := Y
+        0x9B, // Y := X
+        0xEB, // SWAP A, B
+        0x48, 0xAB, // DB := A
+        0x4B, 0xFA, // X := PB
+        0x4B, 0xAB, // DB := PB
+        0xC2, 0x30, 0xA8, 0xBA, 0x7B, 0xE2, 0x30, // [WIDEM, WIDEX]
+        0x60,
+    ];
+    assert_eq!(expected, &bytes[0..expected.len()]);
+
+    let mismatches = [
+        (
+            "[WIDEM] { X := C; }",
+            codegen::Error::InvalidRegister(Register::C, Attribute::NarrowIndex, "main"),
+        ),
+        (
+            "[WIDEX] { A := X; }",
+            codegen::Error::InvalidRegister(Register::A, Attribute::WideIndex, "main"),
+        ),
+        (
+            "[WIDEM] { DB := A; }",
+            codegen::Error::InvalidRegister(Register::A, Attribute::WideMath, "main"),
+        ),
+        (
+            "[WIDEX] { Y := DB; }",
+            codegen::Error::InvalidRegister(Register::Y, Attribute::WideIndex, "main"),
+        ),
+        (
+            "A := B;",
+            codegen::Error::BadAssignment(
+                &Operand::Register(Register::A),
+                &Operand::Register(Register::B),
+                "main",
+            ),
+        ),
+        (
+            "S := X;",
+            codegen::Error::InvalidRegister(Register::S, Attribute::NarrowIndex, "main"),
+        ),
+    ];
+    for (statement, error) in mismatches {
+        let source = format!("FUN main [NAT] {{ {} }}", statement);
+        let ast = parser::program(&source).unwrap();
+        let errors = codegen::assemble(&ast).unwrap_err();
+        assert_eq!(errors[0].error, error, "{}", statement);
+    }
 }
@@ -0,0 +1,191 @@
+use snazzy::emulator::{Machine, Stop, MEMORY};
+use snazzy::{codegen, parser};
+use std::path::Path;
+
+// Assemble a program, and get ready to call one of its functions in
+// native mode with narrow registers
+fn load(source: &str, function: &str) -> (Machine, u32) {
+    let ast = parser::program(source).unwrap();
+    let rom = codegen::assemble_rom(&ast, Path::new("")).unwrap();
+    let symbol = rom.symbols.iter().find(|s| s.name == function).unwrap();
+    let mut machine = Machine::new(rom.image.clone());
+    machine.cpu.emulation = false;
+    (machine, symbol.addr as u32)
+}
+
+#[test]
+fn simple() {
+    let (mut machine, main) = load(include_str!("input/simple.snz"), "main");
+    assert_eq!(machine.call(main, 100), Stop::Returned);
+    assert_eq!(machine.bus.wram[0x1000], 0x30);
+}
+
+#[test]
+fn snes() {
+    let source = include_str!("input/snes.snz");
+    let (mut machine, main) = load(source, "main");
+    let (_, nmi) = load(source, "nmi");
+    machine.reset();
+    assert_eq!(machine.run_to(main, 10_000), Stop::Reached);
+    assert!(!machine.cpu.emulation);
+    assert_eq!(machine.cpu.s, 0x01FD);
+    assert_eq!(machine.bus.writes[0], (0x2100, 0x8F));
+
+    // The main loop waits for NMI to signal it
+    assert_eq!(machine.run(10_000), Stop::Limit);
+    assert!(machine.bus.writes.contains(&(0x4200, 0x81)));
+    assert_eq!(machine.bus.wram[0], 2);
+    assert_eq!(machine.call(nmi, 1000), Stop::Returned);
+    assert_eq!(machine.bus.wram[0], 1);
+}
+
+#[test]
+fn loops() {
+    let source = "
+        VAR total := 0x0010;
+        FUN main {
+          A := 0;
+          FOR X := 1 TO 10 {
+            A += 3;
+          }
+          total := A;
+        }";
+    let (mut machine, main) = load(source, "main");
+    assert_eq!(machine.call(main, 1000), Stop::Returned);
+    assert_eq!(machine.bus.wram[0x10], 30);
+    assert_eq!(machine.cpu.x, 11);
+}
+
+#[test]
+fn long_branches() {
+    // Widening the IF's branch moves the CONTINUE and BREAK inside it,
+    // and the ELSE after it
+    let source = format!(
+        "
+        VAR count := 0x0010;
+        VAR other := 0x0011;
+        FUN main {{
+          X := 0;
+          DO {{
+            X++;
+            IF (X != 2) {{
+              IF (X == 4) {{
+                BREAK;
+              }}
+              count++;
+              CONTINUE;
+              {}
+            }} ELSE {{
+              other++;
+              {}
+            }}
+          }}
+        }}",
+        "A := 0;".repeat(64),
+        "A := 0;".repeat(64)
+    );
+    let (mut machine, main) = load(&source, "main");
+    assert_eq!(machine.call(main, 1000), Stop::Returned);
+    assert_eq!(&machine.bus.wram[0x10..0x12], &[2, 1]);
+    assert_eq!(machine.cpu.x, 4);
+}
+
+#[test]
+fn comparisons() {
+    type Check = fn(u8) -> bool;
+    let checks: [(_, Check); 9] = [
+        ("==", |v| v == 0x10),
+        ("<", |v| v < 0x10),
+        (">=", |v| v >= 0x10),
+        (">", |v| v > 0x10),
+        ("<=", |v| v <= 0x10),
+        ("<s", |v| (v as i8) < 0x10),
+        (">=s", |v| (v as i8) >= 0x10),
+        (">s", |v| (v as i8) > 0x10),
+        ("<=s", |v| (v as i8) <= 0x10),
+    ];
+    // The indirect operand points at a copy of the immediate
+    for rhs in ["0x10", "(D[0x20])"] {
+        for (operator, expected) in checks {
+            let source = format!(
+                "VAR value := 0x0010;
+                VAR result := 0x0011;
+                FUN main {{
+                  A := value;
+                  IF (A {} {}) {{ A := 1; }} ELSE {{ A := 0; }}
+                  result := A;
+                }}",
+                operator, rhs
+            );
+            let (mut machine, main) = load(&source, "main");
+            machine.bus.wram[0x12] = 0x10;
+            machine.bus.wram[0x20] = 0x12;
+            for value in 0..=255 {
+                machine.bus.wram[0x10] = value;
+                assert_eq!(machine.call(main, 100), Stop::Returned);
+                assert_eq!(
+                    machine.bus.wram[0x11] == 1,
+                    expected(value),
+                    "{} {} {}",
+                    value,
+                    operator,
+                    rhs
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn transfers() {
+    let source = "
+        VAR result := 0x0010;
+        FUN main {
+          A := 0x7E;
+          DB := A;
+          A := 0x34;
+          SWAP B, A;
+          A := 0x12;
+          Y := A;
+          [WIDEM, WIDEX] {
+            X := C;
+            result := X;
+          }
+        }";
+    let (mut machine, main) = load(source, "main");
+    assert_eq!(machine.call(main, 100), Stop::Returned);
+    assert_eq!(machine.cpu.db, 0x7E);
+    assert_eq!(machine.cpu.y, 0x12);
+    assert_eq!(&machine.bus.wram[0x10..0x12], &[0x12, 0x34]);
+}
+
+#[test]
+fn bytes() {
+    // Modes changed by BYTES are put back at the end of each pass
+    // round a loop, and at the end of an IF
+    let source = "
+        VAR total := 0x0010;
+        VAR value := 0x0011;
+        VAR result := 0x0012;
+        FUN main [NAT] {
+          FOR X := 0 TO 3 {
+            A := total;
+            A += 1;
+            total := A;
+            BYTES(0xC2, 0x20) [WIDEM];
+          }
+          A := value;
+          IF (A == 1) {
+            BYTES(0xC2, 0x20) [WIDEM];
+          }
+          A := 2;
+          result := A;
+        }";
+    let (mut machine, main) = load(source, "main");
+    for value in 0..2 {
+        machine.bus.wram[0x10..0x14].copy_from_slice(&[0, value, 0, 0xAA]);
+        assert_eq!(machine.call(main, 1000), Stop::Returned);
+        assert_eq!(&machine.bus.wram[0x10..0x14], &[4, value, 2, 0xAA]);
+        assert_eq!(machine.cpu.p & MEMORY, MEMORY);
+    }
+}
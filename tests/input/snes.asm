@@ -0,0 +1,159 @@
+; cop
+RTI
+
+; brk
+RTI
+
+; irq
+RTI
+
+; cop_emu
+RTI
+
+; nmi_emu
+RTI
+
+; irq_emu
+RTI
+
+; reset
+SEI
+CLC
+XCE
+REP #$20
+LDA #$01FF
+TCS
+LDA #$0000
+TCD
+SEP #$30
+LDA #$8F
+STA $2100
+STZ $2101
+STZ $2102
+STZ $2103
+STZ $2105
+STZ $2106
+STZ $2107
+STZ $2108
+STZ $2109
+STZ $210A
+STZ $210B
+STZ $210C
+STZ $210D
+STZ $210D
+LDA #$FF
+STA $210E
+STA $2110
+STA $2112
+STA $2114
+LDA #$07
+STA $210E
+STA $2110
+STA $2112
+STA $2114
+STZ $210F
+STZ $210F
+STZ $2111
+STZ $2111
+STZ $2113
+STZ $2113
+LDA #$80
+STA $2115
+STZ $2116
+STZ $2117
+STZ $211A
+STZ $211B
+LDA #$01
+STA $211B
+STZ $211C
+STZ $211C
+STZ $211D
+STZ $211D
+STZ $211E
+STA $211E
+STZ $211F
+STZ $211F
+STZ $2120
+STZ $2120
+STZ $2121
+STZ $2123
+STZ $2124
+STZ $2125
+STZ $2126
+STZ $2127
+STZ $2128
+STZ $2129
+STZ $212A
+STZ $212B
+STA $212C
+STZ $212D
+STZ $212E
+STZ $212F
+LDA #$30
+STA $2130
+STZ $2131
+LDA #$E0
+STA $2132
+STZ $2133
+LDA #$FF
+STZ $4200
+STA $4201
+STZ $4202
+STZ $4203
+STZ $4204
+STZ $4205
+STZ $4206
+STZ $4207
+STZ $4208
+STZ $4209
+STZ $420A
+STZ $420B
+STZ $420C
+STZ $420D
+CLI
+JSR $8110
+SEC
+XCE
+RTI
+
+; main
+LDA #$3C
+STZ $2122
+STA $2122
+LDA #$02
+STA $0000
+LDA #$81
+STA $4200
+LDA $0000
+BIT #$01
+BEQ $8122
+LDA $0000
+ORA #$02
+AND #$FE
+STA $0000
+BRA $8122
+RTS
+
+; nmi
+REP #$30
+PHA
+PHX
+PHY
+SEP #$30
+LDA $0000
+BIT #$02
+BEQ $8158
+LDA #$8F
+STA $2100
+LDA #$0F
+STA $2100
+LDA $0000
+ORA #$01
+AND #$FD
+STA $0000
+REP #$30
+PLY
+PLX
+PLA
+SEP #$30
+RTI
@@ -1,5 +1,35 @@
 use snazzy::{ast::*, parser};
 
+// The expected ASTs are built without any source text, so compare
+// against the parsed ones with their spans cleared
+fn without_spans(mut program: Program) -> Program {
+    for definition in &mut program.definitions {
+        if let Definition::Function(function) = definition {
+            clear_spans(&mut function.body);
+        }
+    }
+    program
+}
+
+fn clear_spans(block: &mut Block) {
+    for statement in &mut block.instructions {
+        statement.span = Span::default();
+        match &mut statement.instruction {
+            Instruction::Block(block)
+            | Instruction::Loop(block, _)
+            | Instruction::While(block, _)
+            | Instruction::For(block, ..) => clear_spans(block),
+            Instruction::If(block, _, otherwise) => {
+                clear_spans(block);
+                if let Some(otherwise) = otherwise {
+                    clear_spans(otherwise);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
 #[test]
 fn empty() {
     assert!(parser::program("").is_ok())
@@ -18,18 +48,26 @@ fn simple() {
                 body: Block {
                     attributes: vec![],
                     instructions: vec![
-                        Instruction::Assign(Operand::Register(Register::A), Operand::Immediate(48)),
+                        Instruction::Assign(Operand::Register(Register::A), Operand::Immediate(48))
+                            .into(),
                         Instruction::Assign(
                             Operand::Variable("reg"),
                             Operand::Register(Register::A),
-                        ),
+                        )
+                        .into(),
                     ],
                 },
             }),
         ],
     };
-    let result = parser::program(include_str!("input/simple.snz"));
-    assert_eq!(result, Ok(expected));
+    let result = parser::program(include_str!("input/simple.snz")).unwrap();
+    match &result.definitions[1] {
+        Definition::Function(function) => {
+            assert_eq!(function.body.instructions[0].span, Span("A := 0x30;"))
+        }
+        _ => panic!("expected a function"),
+    }
+    assert_eq!(without_spans(result), expected);
 }
 
 #[test]
@@ -83,7 +121,7 @@ fn snes() {
                 body: Block {
                     attributes: vec![Attribute::Emulation, Attribute::Interrupt],
                     instructions: vec![
-                        Instruction::Sei,
+                        Instruction::Sei.into(),
                         Instruction::Block(Block {
                             attributes: vec![Attribute::Native],
                             instructions: vec![
@@ -93,361 +131,451 @@ fn snes() {
                                         Instruction::Assign(
                                             Operand::Register(Register::C),
                                             Operand::Immediate(0x01FF),
-                                        ),
+                                        )
+                                        .into(),
                                         Instruction::Assign(
                                             Operand::Register(Register::S),
                                             Operand::Register(Register::C),
-                                        ),
+                                        )
+                                        .into(),
                                         Instruction::Assign(
                                             Operand::Register(Register::C),
                                             Operand::Immediate(0),
-                                        ),
+                                        )
+                                        .into(),
                                         Instruction::Assign(
                                             Operand::Register(Register::D),
                                             Operand::Register(Register::C),
-                                        ),
+                                        )
+                                        .into(),
                                     ],
-                                }),
+                                })
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0x8F),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2100),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2101),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2102),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2103),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2105),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2106),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2107),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2108),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2109),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210A),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210B),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210C),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210D),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210D),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0xFF),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210E),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2110),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2112),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2114),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0x07),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210E),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2110),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2112),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2114),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210F),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x210F),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2111),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2111),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2113),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2113),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0x80),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2115),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2116),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2117),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211A),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211B),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(1),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211B),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211C),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211C),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211D),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211D),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211E),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211E),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211F),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x211F),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2120),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2120),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2121),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2123),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2124),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2125),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2126),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2127),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2128),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2129),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212A),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212B),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212C),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212D),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212E),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x212F),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0x30),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2130),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2131),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0xE0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2132),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x2133),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Register(Register::A),
                                     Operand::Immediate(0xFF),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4200),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4201),
                                     Operand::Register(Register::A),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4202),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4203),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4204),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4205),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4206),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4207),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4208),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x4209),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x420A),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x420B),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x420C),
                                     Operand::Immediate(0),
-                                ),
+                                )
+                                .into(),
                                 Instruction::Assign(
                                     Operand::Absolute(0x420D),
                                     Operand::Immediate(0),
-                                ),
-                                Instruction::Cli,
-                                Instruction::Call("main"),
+                                )
+                                .into(),
+                                Instruction::Cli.into(),
+                                Instruction::Call("main").into(),
                             ],
-                        }),
+                        })
+                        .into(),
                     ],
                 },
             }),
@@ -475,25 +603,32 @@ fn snes() {
                         Instruction::Assign(
                             Operand::Register(Register::A),
                             Operand::Immediate(0x3C),
-                        ),
-                        Instruction::Assign(Operand::Variable("cgdata"), Operand::Immediate(0)),
+                        )
+                        .into(),
+                        Instruction::Assign(Operand::Variable("cgdata"), Operand::Immediate(0))
+                            .into(),
                         Instruction::Assign(
                             Operand::Variable("cgdata"),
                             Operand::Register(Register::A),
-                        ),
-                        Instruction::Assign(Operand::Register(Register::A), Operand::Immediate(2)),
+                        )
+                        .into(),
+                        Instruction::Assign(Operand::Register(Register::A), Operand::Immediate(2))
+                            .into(),
                         Instruction::Assign(
                             Operand::Variable("status"),
                             Operand::Register(Register::A),
-                        ),
+                        )
+                        .into(),
                         Instruction::Assign(
                             Operand::Register(Register::A),
                             Operand::Immediate(0b1000_0001),
-                        ),
+                        )
+                        .into(),
                         Instruction::Assign(
                             Operand::Variable("nmitimen"),
                             Operand::Register(Register::A),
-                        ),
+                        )
+                        .into(),
                         Instruction::Loop(
                             Block {
                                 attributes: vec![],
@@ -504,33 +639,40 @@ fn snes() {
                                             instructions: vec![Instruction::Assign(
                                                 Operand::Register(Register::A),
                                                 Operand::Variable("status"),
-                                            )],
+                                            )
+                                            .into()],
                                         },
                                         Some(Conditional::NotBitTest(
                                             Operand::Register(Register::A),
                                             Operand::Immediate(1),
                                         )),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Register(Register::A),
                                         Operand::Variable("status"),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::OrAssign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(0b0000_0010),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::AndAssign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(0b1111_1110),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Variable("status"),
                                         Operand::Register(Register::A),
-                                    ),
+                                    )
+                                    .into(),
                                 ],
                             },
                             None,
-                        ),
+                        )
+                        .into(),
                     ],
                 },
             }),
@@ -542,15 +684,17 @@ fn snes() {
                         Instruction::Block(Block {
                             attributes: vec![Attribute::WideMath, Attribute::WideIndex],
                             instructions: vec![
-                                Instruction::Push(Operand::Register(Register::C)),
-                                Instruction::Push(Operand::Register(Register::X)),
-                                Instruction::Push(Operand::Register(Register::Y)),
+                                Instruction::Push(Operand::Register(Register::C)).into(),
+                                Instruction::Push(Operand::Register(Register::X)).into(),
+                                Instruction::Push(Operand::Register(Register::Y)).into(),
                             ],
-                        }),
+                        })
+                        .into(),
                         Instruction::Assign(
                             Operand::Register(Register::A),
                             Operand::Variable("status"),
-                        ),
+                        )
+                        .into(),
                         Instruction::If(
                             Block {
                                 attributes: vec![],
@@ -558,50 +702,61 @@ fn snes() {
                                     Instruction::Assign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(0x8F),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Variable("inidisp"),
                                         Operand::Register(Register::A),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(0x0F),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Variable("inidisp"),
                                         Operand::Register(Register::A),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Register(Register::A),
                                         Operand::Variable("status"),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::OrAssign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(1),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::AndAssign(
                                         Operand::Register(Register::A),
                                         Operand::Immediate(0b1111_1101),
-                                    ),
+                                    )
+                                    .into(),
                                     Instruction::Assign(
                                         Operand::Variable("status"),
                                         Operand::Register(Register::A),
-                                    ),
+                                    )
+                                    .into(),
                                 ],
                             },
                             Conditional::BitTest(
                                 Operand::Register(Register::A),
                                 Operand::Immediate(2),
                             ),
-                        ),
+                            None,
+                        )
+                        .into(),
                         Instruction::Block(Block {
                             attributes: vec![Attribute::WideMath, Attribute::WideIndex],
                             instructions: vec![
-                                Instruction::Pop(Operand::Register(Register::Y)),
-                                Instruction::Pop(Operand::Register(Register::X)),
-                                Instruction::Pop(Operand::Register(Register::C)),
+                                Instruction::Pop(Operand::Register(Register::Y)).into(),
+                                Instruction::Pop(Operand::Register(Register::X)).into(),
+                                Instruction::Pop(Operand::Register(Register::C)).into(),
                             ],
-                        }),
+                        })
+                        .into(),
                     ],
                 },
             }),
@@ -609,5 +764,5 @@ fn snes() {
     };
 
     let result = parser::program(include_str!("input/snes.snz"));
-    assert_eq!(result, Ok(expected));
+    assert_eq!(result.map(without_spans), Ok(expected));
 }